@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use topflight_definitions::Instruction;
+
+// Any line can be given to the parser, it must fail without panicking
+fuzz_target!(|line: &str| {
+    if let Ok(instruction) = Instruction::parse(line) {
+        // Used by the checker and the tracer on every parsed instruction
+        let _ = instruction.read_variables();
+        let _ = instruction.written_variables();
+        let _ = instruction.called_routine();
+    }
+});
@@ -0,0 +1,29 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use topflight_core::{Interpreter, VirtualFiles};
+
+// Enough for the examples, small enough to keep the fuzzer fast
+const MAX_STEPS: u64 = 10_000;
+const MAX_MEMORY: usize = 1 << 20;
+
+// A whole program is parsed then executed, like the command line interpreter does. The errors are
+// expected, the panics are bugs.
+fuzz_target!(|source: &str| {
+    let mut interpreter = Interpreter::default();
+    // Only the standard library can be imported, the fuzzer must not read the disk
+    interpreter.set_file_loader(VirtualFiles::default());
+    interpreter.limits.max_steps = Some(MAX_STEPS);
+    interpreter.memory.set_max_size(Some(MAX_MEMORY));
+
+    let program = match interpreter.load_source(source) {
+        Ok(program) => program,
+        Err(_) => return,
+    };
+    let mut output = String::new();
+    for (_, instruction) in program {
+        if interpreter.execute(&instruction, &mut output).is_err() {
+            return;
+        }
+    }
+});
@@ -0,0 +1,18 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use topflight_definitions::Value;
+
+// A parsed literal rendered again must give the same value
+fuzz_target!(|literal: &str| {
+    let value: Value = match literal.parse() {
+        Ok(value) => value,
+        Err(_) => return,
+    };
+    let rendered = value.literal();
+    let parsed: Value = rendered
+        .parse()
+        .unwrap_or_else(|error| panic!("`{}` is not parsed back: {}", rendered, error));
+    // Compared as literals because NaN is not equal to itself
+    assert_eq!(parsed.literal(), rendered);
+});
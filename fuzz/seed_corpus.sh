@@ -0,0 +1,23 @@
+#!/bin/sh
+# Fills the corpus of every fuzz target from the example programs
+set -e
+cd "$(dirname "$0")"
+examples=../TopFlightExamples
+
+mkdir -p corpus/program corpus/instruction_parse corpus/value_round_trip
+for program in "$examples"/*.tpf ../topflight_core/stdlib/*.tpf; do
+    cp "$program" corpus/program/
+done
+
+# One file per distinct instruction, and per distinct literal stored by a STORE
+i=0
+cat "$examples"/*.tpf | tr -d '\r' | grep -v '^#' | grep -v '^<' | grep -v '^$' | sort -u |
+    while IFS= read -r line; do
+        i=$((i + 1))
+        printf '%s' "$line" > "corpus/instruction_parse/example_$i"
+        case "$line" in
+        STORE\ *)
+            printf '%s' "${line#STORE * }" > "corpus/value_round_trip/example_$i"
+            ;;
+        esac
+    done
@@ -0,0 +1,242 @@
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use topflight_core::{
+    Breakpoint, Debugger, Interpreter, Limits, Location, Memory, Pause, Routines, VMError, Value,
+};
+
+use crate::repl::print_output;
+use crate::LoadedFile;
+
+const PROMPT: &str = "(debug) ";
+
+const HELP: &str = "break line         Stop before the instruction at this line
+break routine      Stop when the routine is called
+delete line        Remove a breakpoint, same for a routine
+run                Run until a breakpoint or the end of the program
+step               Execute one instruction, entering the called routines
+next               Execute one instruction, running the called routines entirely
+finish             Run until the current routine returns
+print var          Print the value of a variable
+set var LITERAL    Change the value of a variable, for example: set i INTEGER(5)
+bt                 Print the call stack
+vars               List the variables stored in memory
+help               Print this message
+quit               Leave the debugger
+";
+
+struct Session {
+    interpreter: Interpreter,
+    debugger: Debugger,
+    lines: Vec<String>,
+}
+
+impl Session {
+    fn new(file: LoadedFile) -> Session {
+        Session {
+            interpreter: file.interpreter,
+            debugger: Debugger::new(file.program),
+            lines: file.lines,
+        }
+    }
+
+    // Returns false if the user wants to leave
+    fn handle_command(&mut self, command: &str) -> bool {
+        let mut words = command.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("break" | "b"), Some(target)) => self.add_breakpoint(target),
+            (Some("delete" | "d"), Some(target)) => self.remove_breakpoint(target),
+            (Some("run" | "r" | "continue" | "c"), None) => self.resume(Debugger::resume),
+            (Some("step" | "s"), None) => self.resume(Debugger::step_into),
+            (Some("next" | "n"), None) => self.resume(Debugger::step_over),
+            (Some("finish"), None) => self.resume(Debugger::step_out),
+            (Some("print" | "p"), Some(name)) => match self.interpreter.memory.load(name) {
+                Ok(value) => println!("{} = {}", name, value.literal()),
+                Err(error) => println!("{}", error),
+            },
+            (Some("set"), Some(name)) => {
+                self.set(name, after_two_words(command));
+                return true;
+            }
+            (Some("bt"), None) => self.print_call_stack(),
+            (Some("vars"), None) => self.print_vars(),
+            (Some("help" | "h"), None) => print!("{}", HELP),
+            (Some("quit" | "q"), None) => return false,
+            _ => println!(
+                "Unknown command `{}`, type help to list the commands",
+                command
+            ),
+        }
+        if words.next().is_some() {
+            println!("Too many arguments in `{}`", command);
+        }
+        true
+    }
+
+    fn add_breakpoint(&mut self, target: &str) {
+        let breakpoint = breakpoint(target);
+        println!("Breakpoint on {}", describe(&breakpoint));
+        self.debugger.add_breakpoint(breakpoint);
+    }
+
+    fn remove_breakpoint(&mut self, target: &str) {
+        let breakpoint = breakpoint(target);
+        if !self.debugger.remove_breakpoint(&breakpoint) {
+            println!("There is no breakpoint on {}", describe(&breakpoint));
+        }
+    }
+
+    fn resume(
+        &mut self,
+        action: impl FnOnce(
+            &mut Debugger,
+            &mut Memory,
+            &Routines,
+            &mut String,
+            &mut Limits,
+        ) -> Result<Pause, VMError>,
+    ) {
+        if self.debugger.is_finished() {
+            println!("The program is finished");
+            return;
+        }
+
+        let mut output = String::new();
+        let result = action(
+            &mut self.debugger,
+            &mut self.interpreter.memory,
+            &self.interpreter.routines,
+            &mut output,
+            &mut self.interpreter.limits,
+        );
+        print_output(&output);
+
+        match result {
+            Ok(Pause::Finished) => println!("The program is finished"),
+            Ok(Pause::Breakpoint(breakpoint)) => {
+                println!("Breakpoint on {}", describe(&breakpoint));
+                self.print_location();
+            }
+            Ok(Pause::Step) => self.print_location(),
+            Err(error) => {
+                println!("Error: {}", error);
+                self.print_location();
+            }
+        }
+    }
+
+    fn set(&mut self, name: &str, literal: &str) {
+        match literal.parse::<Value>() {
+            Ok(value) => self.interpreter.memory.store(name, value),
+            Err(error) => println!("Invalid literal `{}`: {}", literal, error),
+        }
+    }
+
+    fn print_location(&self) {
+        if let Some(location) = self.debugger.location(&self.interpreter.routines) {
+            println!("{}", self.describe_location(&location));
+        }
+    }
+
+    fn print_call_stack(&self) {
+        let call_stack = self.debugger.call_stack(&self.interpreter.routines);
+        for (i, location) in call_stack.iter().rev().enumerate() {
+            println!("#{} {}", i, self.describe_location(location));
+        }
+    }
+
+    fn print_vars(&self) {
+        let mut values: Vec<_> = self.interpreter.memory.iter().collect();
+        values.sort_by_key(|(name, _)| *name);
+        for (name, value) in values {
+            println!("{} = {}", name, value.literal());
+        }
+    }
+
+    fn describe_location(&self, location: &Location) -> String {
+        let routine = location.routine.as_deref().unwrap_or("top level");
+        match location.line {
+            0 => format!("{}, instruction {}", routine, location.index + 1),
+            line => format!(
+                "{} at line {}:\t{}",
+                routine,
+                line,
+                self.lines[line - 1].trim()
+            ),
+        }
+    }
+}
+
+// The text after the command and its first argument as typed, the spaces of a string literal are
+// kept
+fn after_two_words(command: &str) -> &str {
+    let mut rest = command;
+    for _ in 0..2 {
+        rest = rest.trim_start();
+        rest = rest
+            .find(char::is_whitespace)
+            .map_or("", |end| &rest[end..]);
+    }
+    rest.trim()
+}
+
+// A number is a line, anything else a routine name
+fn breakpoint(target: &str) -> Breakpoint {
+    match target.parse() {
+        Ok(line) => Breakpoint::Line(line),
+        Err(_) => Breakpoint::Routine(target.to_string()),
+    }
+}
+
+fn describe(breakpoint: &Breakpoint) -> String {
+    match breakpoint {
+        Breakpoint::Line(line) => format!("line {}", line),
+        Breakpoint::Routine(name) => format!("routine `{}`", name),
+    }
+}
+
+pub fn run(file: LoadedFile) {
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(error) => {
+            println!("Unable to start the debugger: {}", error);
+            return;
+        }
+    };
+    let mut session = Session::new(file);
+    println!("Type help to list the commands");
+    session.print_location();
+
+    loop {
+        let line = match editor.readline(PROMPT) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(error) => {
+                println!("Error while reading the input: {}", error);
+                break;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line.as_str());
+        if !session.handle_command(line.as_str()) {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_after_two_words() {
+        assert_eq!(
+            after_two_words("set s  STRING(\"a  \tb\") "),
+            "STRING(\"a  \tb\")"
+        );
+        assert_eq!(after_two_words(" set\ti   INTEGER(5)"), "INTEGER(5)");
+        assert_eq!(after_two_words("set i"), "");
+    }
+}
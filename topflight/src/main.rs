@@ -1,61 +1,275 @@
 use std::env;
 use std::fmt;
-use std::fs::File;
-use std::io::{self, BufRead};
-use std::path::Path;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use topflight_core::{Instruction, Observer};
 
-fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
-where
-    P: AsRef<Path>,
-{
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
-}
+mod debug;
+mod repl;
+mod test;
 
 #[derive(Debug)]
-struct Error {
-    wrapped_error: topflight_core::Error,
-    line_number: usize,
-    line: String,
+enum Error {
+    // The file itself cannot be read
+    Read {
+        filename: String,
+        error: io::Error,
+    },
+    // The program is invalid or failed at this line
+    Line {
+        wrapped_error: topflight_core::Error,
+        line_number: usize,
+        line: String,
+    },
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "Error at line {}: {}\n\t{}",
-            self.line_number, self.wrapped_error, self.line
-        )
+        match self {
+            Error::Read { filename, error } => {
+                write!(f, "Error while opening the file `{}`: {}", filename, error)
+            }
+            Error::Line {
+                wrapped_error,
+                line_number,
+                line,
+            } => write!(
+                f,
+                "Error at line {}: {}\n\t{}",
+                line_number, wrapped_error, line
+            ),
+        }
+    }
+}
+
+// Options given before the file to execute
+#[derive(Default)]
+struct Options {
+    max_steps: Option<u64>,
+    max_call_depth: Option<usize>,
+    max_memory: Option<usize>,
+    timeout: Option<Duration>,
+    trace: Option<topflight_core::TraceFormat>,
+    profile: bool,
+    profile_folded: Option<PathBuf>,
+    coverage: Option<PathBuf>,
+}
+
+impl Options {
+    // Limits of an execution starting now
+    fn limits(&self) -> topflight_core::Limits {
+        let mut limits = topflight_core::Limits::default();
+        limits.max_steps = self.max_steps;
+        limits.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        if self.max_call_depth.is_some() {
+            limits.max_call_depth = self.max_call_depth;
+        }
+        limits
+    }
+
+    fn memory(&self) -> topflight_core::Memory {
+        let mut memory = topflight_core::Memory::default();
+        memory.set_max_size(self.max_memory);
+        memory
+    }
+}
+
+fn parse_options(args: &mut Vec<String>) -> Result<Options, String> {
+    let mut options = Options::default();
+    while args.first().is_some_and(|arg| arg.starts_with("--")) {
+        let option = args.remove(0);
+        match option.as_str() {
+            "--max-steps" => options.max_steps = Some(option_value(&option, args)?),
+            "--max-call-depth" => options.max_call_depth = Some(option_value(&option, args)?),
+            "--max-memory" => {
+                let size: Size = option_value(&option, args)?;
+                options.max_memory = Some(size.0);
+            }
+            "--timeout" => {
+                let timeout: Timeout = option_value(&option, args)?;
+                options.timeout = Some(timeout.0);
+            }
+            "--trace" => options.trace = Some(topflight_core::TraceFormat::Text),
+            "--trace-json" => options.trace = Some(topflight_core::TraceFormat::Json),
+            "--profile" => options.profile = true,
+            "--profile-folded" => options.profile_folded = Some(option_value(&option, args)?),
+            "--coverage" => options.coverage = Some(option_value(&option, args)?),
+            _ => return Err(format!("Unknown option `{}`", option)),
+        }
     }
+    Ok(options)
 }
 
-fn execute_file<P>(filename: P, args: Vec<String>) -> Result<(), Error>
+// A number of bytes, with an optional suffix: 64K, 512M, 2G
+struct Size(usize);
+
+impl FromStr for Size {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        let (number, multiplier) = match str.char_indices().last() {
+            Some((i, 'K')) => (&str[..i], 1 << 10),
+            Some((i, 'M')) => (&str[..i], 1 << 20),
+            Some((i, 'G')) => (&str[..i], 1 << 30),
+            _ => (str, 1),
+        };
+        Ok(Size(number.parse::<usize>()?.saturating_mul(multiplier)))
+    }
+}
+
+// A duration in seconds, or with a unit: 500ms, 5s, 2m
+struct Timeout(Duration);
+
+impl FromStr for Timeout {
+    type Err = std::num::ParseFloatError;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        let (number, seconds_per_unit) = if let Some(number) = str.strip_suffix("ms") {
+            (number, 0.001)
+        } else if let Some(number) = str.strip_suffix('s') {
+            (number, 1.0)
+        } else if let Some(number) = str.strip_suffix('m') {
+            (number, 60.0)
+        } else {
+            (str, 1.0)
+        };
+        let seconds = number.parse::<f64>()? * seconds_per_unit;
+        // Negative, NaN or too big timeouts are clamped instead of making the conversion panic
+        let seconds = seconds.max(0.0).min(u32::MAX as f64);
+        Ok(Timeout(Duration::from_secs_f64(seconds)))
+    }
+}
+
+fn option_value<T: FromStr>(option: &str, args: &mut Vec<String>) -> Result<T, String> {
+    if args.is_empty() {
+        return Err(format!("`{}` expects a value", option));
+    }
+    let value = args.remove(0);
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value `{}` for `{}`", value, option))
+}
+
+// A file whose routines are defined and whose top level instructions are ready to be executed
+struct LoadedFile {
+    interpreter: topflight_core::Interpreter,
+    program: Vec<(usize, Instruction)>,
+    lines: Vec<String>,
+}
+
+fn error_at(lines: &[String], wrapped_error: topflight_core::Error, line_number: usize) -> Error {
+    Error::Line {
+        wrapped_error,
+        line_number,
+        line: lines[line_number - 1].clone(),
+    }
+}
+
+fn load_file<P>(filename: P, args: Vec<String>, options: &Options) -> Result<LoadedFile, Error>
 where
     P: AsRef<Path>,
 {
-    let mut memory = topflight_core::Memory::default();
+    let mut interpreter = topflight_core::Interpreter::default();
+    interpreter.set_file_path(Some(filename.as_ref()));
+    interpreter.limits = options.limits();
+    interpreter.memory = options.memory();
     if !args.is_empty() {
-        memory.store("args", topflight_core::Value::ArrayOfString(args));
+        interpreter
+            .memory
+            .store("args", topflight_core::Value::from(args));
+    }
+
+    let source = fs::read_to_string(&filename).map_err(|error| Error::Read {
+        filename: filename.as_ref().display().to_string(),
+        error,
+    })?;
+    let lines: Vec<String> = source.lines().map(String::from).collect();
+    // Define every routine first so they can be called before their definition
+    match interpreter.load_source(&source) {
+        Ok(program) => Ok(LoadedFile {
+            interpreter,
+            program,
+            lines,
+        }),
+        Err((error, line_number)) => Err(error_at(&lines, error, line_number)),
     }
+}
+
+fn execute_file<P>(filename: P, args: Vec<String>, options: &Options) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+{
+    let source_file = filename.as_ref().display().to_string();
+    let mut file = load_file(filename, args, options)?;
+    let program = std::mem::take(&mut file.program);
+    // The trace and the report go to the error output to be kept apart from the output of the program
+    let mut tracer = options
+        .trace
+        .map(|format| topflight_core::Tracer::new(format, io::BufWriter::new(io::stderr())));
+    let mut profiler =
+        (options.profile || options.profile_folded.is_some()).then(topflight_core::Profiler::new);
+    let mut coverage = options
+        .coverage
+        .as_ref()
+        .map(|_| topflight_core::Coverage::new(&program, &file.interpreter));
+
+    let mut observers: Vec<&mut dyn Observer> = Vec::new();
+    if let Some(tracer) = tracer.as_mut() {
+        observers.push(tracer);
+    }
+    if let Some(profiler) = profiler.as_mut() {
+        observers.push(profiler);
+    }
+    if let Some(coverage) = coverage.as_mut() {
+        observers.push(coverage);
+    }
+    let observer: Option<&mut dyn Observer> = match observers.is_empty() {
+        true => None,
+        false => Some(&mut observers),
+    };
+    let result = run_program(&mut file, program, observer);
+    drop(observers);
+    drop(tracer);
+
+    if let Some(profiler) = profiler {
+        if options.profile {
+            eprint!("{}", profiler.report());
+        }
+        if let Some(path) = &options.profile_folded {
+            if let Err(error) = fs::write(path, profiler.folded_stacks()) {
+                eprintln!("Unable to write `{}`: {}", path.display(), error);
+            }
+        }
+    }
+    if let (Some(coverage), Some(path)) = (coverage, &options.coverage) {
+        print_coverage(&coverage);
+        if let Err(error) = fs::write(path, coverage.lcov(&source_file)) {
+            eprintln!("Unable to write `{}`: {}", path.display(), error);
+        }
+    }
+    result
+}
 
-    let mut routines = topflight_core::Routines::new();
-    let mut routine_in_construction: Option<topflight_core::Routine> = Option::None;
-    let lines = read_lines(filename).expect("Error while opening the file");
-    for (i, line) in lines.enumerate() {
+// An observer makes the execution slower, so it is optional
+fn run_program(
+    file: &mut LoadedFile,
+    program: Vec<(usize, Instruction)>,
+    mut observer: Option<&mut dyn Observer>,
+) -> Result<(), Error> {
+    for (line_number, instruction) in program {
         let mut output = String::new();
-        let line = line.expect("Error while reading a line");
-        if let Err(error) = topflight_core::handle_line(
-            line.as_str(),
-            &mut memory,
-            &mut routines,
-            &mut routine_in_construction,
-            &mut output,
-        ) {
-            return Err(Error {
-                wrapped_error: error,
-                line_number: i + 1,
-                line
-            });
+        let result = match observer.as_deref_mut() {
+            Some(observer) => {
+                file.interpreter
+                    .execute_observed(&instruction, line_number, &mut output, observer)
+            }
+            None => file.interpreter.execute(&instruction, &mut output),
+        };
+        if let Err(error) = result {
+            return Err(error_at(&file.lines, error, line_number));
         } else if !output.is_empty() {
             print!("{}", output);
         }
@@ -63,13 +277,109 @@ where
     Ok(())
 }
 
+fn print_coverage(coverage: &topflight_core::Coverage) {
+    eprintln!(
+        "{} of {} lines executed",
+        coverage.executed_lines(),
+        coverage.total_lines()
+    );
+    let never_called = coverage.never_called();
+    if !never_called.is_empty() {
+        eprintln!("Routines never called: {}", never_called.join(", "));
+    }
+    let imported = coverage.imported();
+    if !imported.is_empty() {
+        eprintln!(
+            "Routines imported from other files, not measured: {}",
+            imported.join(", ")
+        );
+    }
+}
+
+// Returns false if at least one error (not just a warning) was found
+fn check_files(filenames: &[String]) -> bool {
+    let mut is_valid = true;
+    for filename in filenames {
+        let source = match fs::read_to_string(filename) {
+            Ok(source) => source,
+            Err(error) => {
+                println!("{}: cannot read the file: {}", filename, error);
+                is_valid = false;
+                continue;
+            }
+        };
+
+        let lines: Vec<&str> = source.lines().collect();
+        for diagnostic in topflight_core::check(source.as_str()) {
+            let severity = if diagnostic.problem.is_error() {
+                is_valid = false;
+                "error"
+            } else {
+                "warning"
+            };
+            println!(
+                "{}:{}: {}: {}\n\t{}",
+                filename,
+                diagnostic.line_number,
+                severity,
+                diagnostic.problem,
+                lines[diagnostic.line_number - 1]
+            );
+        }
+    }
+    is_valid
+}
+
 fn main() {
     let mut args: Vec<String> = env::args().skip(1).collect();
-    if !args.is_empty() {
+    if args.first().map(String::as_str) == Some("check") {
+        if !check_files(&args[1..]) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let subcommand = match args.first().map(String::as_str) {
+        Some("debug" | "test") => Some(args.remove(0)),
+        _ => None,
+    };
+
+    let options = match parse_options(&mut args) {
+        Ok(options) => options,
+        Err(error) => {
+            println!("{}", error);
+            std::process::exit(1);
+        }
+    };
+    if subcommand.as_deref() == Some("test") {
+        if args.is_empty() {
+            println!("Usage: topflight test [options] file.tpf...");
+            std::process::exit(1);
+        }
+        if !test::run(&args, &options) {
+            std::process::exit(1);
+        }
+    } else if subcommand.as_deref() == Some("debug") {
+        if args.is_empty() {
+            println!("Usage: topflight debug [options] file.tpf [args]");
+            std::process::exit(1);
+        }
+        let filename = args.remove(0);
+        match load_file(filename, args, &options) {
+            Ok(file) => debug::run(file),
+            Err(error) => {
+                println!("{}", error);
+                std::process::exit(1);
+            }
+        }
+    } else if !args.is_empty() {
         let filename = args.remove(0);
         println!("{}", filename);
-        if let Err(error) = execute_file(filename, args) {
+        if let Err(error) = execute_file(filename, args, &options) {
             println!("{}", error);
+            std::process::exit(1);
         };
+    } else {
+        repl::run();
     }
 }
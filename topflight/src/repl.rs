@@ -0,0 +1,185 @@
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::fs;
+use std::path::Path;
+
+const PROMPT: &str = "topflight> ";
+const CONTINUATION_PROMPT: &str = "       ... ";
+
+const HELP: &str = ":help              Print this message
+:vars              List the variables stored in memory
+:routines          List the defined routines
+:reset             Forget every variable and routine
+:load file.tpf     Execute a file in the current session
+:quit              Leave the interpreter
+";
+
+#[derive(Default)]
+struct Session {
+    interpreter: topflight_core::Interpreter,
+}
+
+impl Session {
+    fn handle_line(&mut self, line: &str) -> Result<String, topflight_core::Error> {
+        let mut output = String::new();
+        self.interpreter.handle_line(line, &mut output)?;
+        Ok(output)
+    }
+
+    fn prompt(&self) -> &'static str {
+        match self.interpreter.routine_in_construction() {
+            None => PROMPT,
+            Some(_) => CONTINUATION_PROMPT,
+        }
+    }
+
+    fn print_vars(&self) {
+        let mut values: Vec<_> = self.interpreter.memory.iter().collect();
+        values.sort_by_key(|(name, _)| *name);
+        for (name, value) in values {
+            println!("{} = {}", name, value.literal());
+        }
+    }
+
+    fn print_routines(&self) {
+        let mut names: Vec<_> = self.interpreter.routines.keys().collect();
+        names.sort();
+        for name in names {
+            println!("{}", name);
+        }
+    }
+
+    fn load(&mut self, filename: &str) {
+        // The lines of the file would end up in the routine
+        if let Some(routine) = self.interpreter.routine_in_construction() {
+            println!("Close the routine `{}` before loading a file", routine.name);
+            return;
+        }
+        let source = match fs::read_to_string(filename) {
+            Ok(source) => source,
+            Err(error) => {
+                println!("Error while reading the file `{}`: {}", filename, error);
+                return;
+            }
+        };
+
+        // Same two phases as when executing a file
+        let lines: Vec<&str> = source.lines().collect();
+        let print_error = |error, line_number: usize| {
+            let line = line_number
+                .checked_sub(1)
+                .and_then(|index| lines.get(index))
+                .unwrap_or(&"");
+            println!("Error at line {}: {}\n\t{}", line_number, error, line);
+        };
+
+        // The files imported by the loaded file are relative to it
+        self.interpreter.set_file_path(Some(Path::new(filename)));
+        let program = self.interpreter.load_source(&source);
+        self.interpreter.set_file_path(None);
+        let program = match program {
+            Ok(program) => program,
+            Err((error, line_number)) => {
+                print_error(error, line_number);
+                return;
+            }
+        };
+
+        let mut output = String::new();
+        for (line_number, instruction) in program {
+            if let Err(error) = self.interpreter.execute(&instruction, &mut output) {
+                print!("{}", output);
+                print_error(error, line_number);
+                return;
+            }
+        }
+        print_output(&output);
+    }
+
+    // Returns false if the user wants to leave
+    fn handle_meta_command(&mut self, command: &str) -> bool {
+        let mut words = command.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some(":help"), None) => print!("{}", HELP),
+            (Some(":vars"), None) => self.print_vars(),
+            (Some(":routines"), None) => self.print_routines(),
+            (Some(":reset"), None) => *self = Session::default(),
+            (Some(":load"), Some(filename)) => self.load(filename),
+            (Some(":quit"), None) => return false,
+            _ => println!(
+                "Unknown command `{}`, type :help to list the commands",
+                command
+            ),
+        }
+        true
+    }
+}
+
+// Keep the prompt on its own line even if the program did not print a line return
+pub(crate) fn print_output(output: &str) {
+    print!("{}", output);
+    if !output.is_empty() && !output.ends_with('\n') {
+        println!();
+    }
+}
+
+pub fn run() {
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(error) => {
+            println!("Unable to start the interactive interpreter: {}", error);
+            return;
+        }
+    };
+    let mut session = Session::default();
+
+    loop {
+        let line = match editor.readline(session.prompt()) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(error) => {
+                println!("Error while reading the input: {}", error);
+                break;
+            }
+        };
+        if !line.trim().is_empty() {
+            let _ = editor.add_history_entry(line.as_str());
+        }
+
+        if line.starts_with(':') {
+            if !session.handle_meta_command(line.as_str()) {
+                break;
+            }
+            continue;
+        }
+
+        match session.handle_line(line.as_str()) {
+            Ok(output) => print_output(&output),
+            Err(error) => println!("Error: {}", error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failed_load() {
+        let path = std::env::temp_dir().join("topflight_repl_failed_load.tpf");
+        fs::write(&path, "<r>\nNOPE x\n</r>\n").unwrap();
+        let mut session = Session::default();
+        session.load(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+
+        // The session is back at the top level, not inside the routine of the file
+        assert_eq!(session.prompt(), PROMPT);
+        let output = session
+            .handle_line("STORE s STRING(\"visible\")")
+            .and_then(|_| session.handle_line("PRINT s"))
+            .unwrap();
+        assert_eq!(output, "visible");
+        assert!(session.interpreter.routines.is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+use topflight_core::{Call, Debugger, Instruction, Pause};
+
+use crate::{load_file, LoadedFile, Options};
+
+// Result of a test, None when it passed
+type Failure = Option<String>;
+
+// Runs the routines named `test_*` of every file, each in a fresh memory. The top level
+// instructions of the files are not executed. Returns false if a test failed.
+pub fn run(filenames: &[String], options: &Options) -> bool {
+    let (mut passed, mut failed) = (0, 0);
+    for filename in filenames {
+        let file = match load_file(filename, Vec::new(), options) {
+            Ok(file) => file,
+            Err(error) => {
+                println!("{}: {}", filename, error);
+                failed += 1;
+                continue;
+            }
+        };
+
+        for test in tests(&file) {
+            match run_test(&file, &test, options) {
+                None => {
+                    println!("test {}::{} ... ok", filename, test);
+                    passed += 1;
+                }
+                Some(message) => {
+                    println!("test {}::{} ... FAILED\n{}", filename, test, message);
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    println!("\n{} passed, {} failed", passed, failed);
+    failed == 0
+}
+
+// The tests defined in the file, not the imported ones, in the order of the file
+fn tests(file: &LoadedFile) -> Vec<String> {
+    let mut tests: Vec<(usize, &String)> = file
+        .interpreter
+        .declared_routines()
+        .iter()
+        .filter(|(name, _)| name.starts_with("test_"))
+        .map(|(name, start)| (*start, name))
+        .collect();
+    tests.sort();
+    tests.into_iter().map(|(_, name)| name.clone()).collect()
+}
+
+fn run_test(file: &LoadedFile, test: &str, options: &Options) -> Failure {
+    let routines = &file.interpreter.routines;
+    let call = Instruction::Call(Call {
+        routine_name: test.to_string(),
+    });
+    // The debugger knows where the test failed
+    let mut debugger = Debugger::new(vec![(0, call)]);
+    let mut memory = options.memory();
+    let mut limits = options.limits();
+    let mut output = String::new();
+
+    let error = match debugger.resume(&mut memory, routines, &mut output, &mut limits) {
+        Ok(Pause::Finished) => return None,
+        // There is no breakpoint, the test should have run until its end
+        Ok(pause) => return Some(format!("\tThe test stopped before its end: {:?}", pause)),
+        Err(error) => error,
+    };
+    let mut message = format!("\t{}", error);
+    if let Some(location) = debugger.location(routines).filter(|l| l.line != 0) {
+        message += &format!(
+            "\n\tat line {} in {}:\t{}",
+            location.line,
+            location.routine.as_deref().unwrap_or(test),
+            file.lines[location.line - 1].trim()
+        );
+    }
+    if !output.is_empty() {
+        message += "\n\toutput:\n";
+        for line in output.lines() {
+            message += &format!("\t\t{}\n", line);
+        }
+        message.pop();
+    }
+    Some(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use topflight_core::{Interpreter, VirtualFiles};
+
+    use super::*;
+
+    #[test]
+    fn test_imported_tests_are_not_listed() {
+        let mut virtual_files = VirtualFiles::default();
+        virtual_files.add(
+            "lib.tpf",
+            String::from("<test_lib>\nASSERT yes\n</test_lib>\n"),
+        );
+        let mut interpreter = Interpreter::default();
+        interpreter.set_file_loader(virtual_files);
+        interpreter.set_file_path(Some(Path::new("main.tpf")));
+        let source = "<test_second>
+ASSERT yes
+</test_second>
+IMPORT lib.tpf
+<test_empty>
+</test_empty>
+<helper>
+</helper>
+";
+        let program = interpreter.load_source(source).unwrap();
+
+        let lines = source.lines().map(String::from).collect();
+        let file = LoadedFile {
+            interpreter,
+            program,
+            lines,
+        };
+        assert!(file.interpreter.routines.contains_key("test_lib"));
+        assert_eq!(tests(&file), vec!["test_second", "test_empty"]);
+    }
+
+    #[test]
+    fn test_run_test() {
+        let source = "<test_ok>\nSTORE yes BOOLEAN(true)\nASSERT yes\n</test_ok>\n<test_ko>\nSTORE no BOOLEAN(false)\nASSERT no\n</test_ko>\n";
+        let mut interpreter = Interpreter::default();
+        let program = interpreter.load_source(source).unwrap();
+        let file = LoadedFile {
+            interpreter,
+            program,
+            lines: source.lines().map(String::from).collect(),
+        };
+
+        let options = Options::default();
+        assert_eq!(run_test(&file, "test_ok", &options), None);
+        assert_eq!(
+            run_test(&file, "test_ko", &options).unwrap(),
+            "\tAssertion failed: `no` is false\n\tat line 7 in test_ko:\tASSERT no"
+        );
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let missing = std::env::temp_dir().join("topflight_test_missing_file.tpf");
+        let filenames = [missing.display().to_string()];
+        assert!(!run(&filenames, &Options::default()));
+    }
+}
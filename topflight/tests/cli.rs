@@ -0,0 +1,31 @@
+// Runs the `topflight` binary like a script would
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus};
+
+fn run(name: &str, source: &str, options: &[&str]) -> ExitStatus {
+    let path: PathBuf = std::env::temp_dir().join(format!("topflight_cli_{}.tpf", name));
+    fs::write(&path, source).expect("Writable temporary file");
+    let output = Command::new(env!("CARGO_BIN_EXE_topflight"))
+        .args(options)
+        .arg(&path)
+        .output()
+        .expect("The binary runs");
+    let _ = fs::remove_file(&path);
+    output.status
+}
+
+const LOOP: &str =
+    "<forever>\nCALL_IF forever yes\n</forever>\nSTORE yes BOOLEAN(true)\nCALL forever\n";
+
+#[test]
+fn test_exit_status() {
+    assert!(run("valid", "STORE a INTEGER(1)\nPRINT a\n", &[]).success());
+    assert!(!run("invalid", "PRINT missing\n", &[]).success());
+
+    // A program stopped by a limit did not succeed
+    assert!(!run("max_steps", LOOP, &["--max-steps", "1000"]).success());
+    assert!(!run("timeout", LOOP, &["--timeout", "50ms"]).success());
+    let memory = "STORE a ARRAY_OF_INTEGER(0)\nSTORE n INTEGER(1000000)\nRESIZE a n\n";
+    assert!(!run("max_memory", memory, &["--max-memory", "1K"]).success());
+}
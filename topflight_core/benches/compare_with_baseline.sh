@@ -0,0 +1,45 @@
+#!/bin/bash
+# Times the programs of benches/programs with `topflight` built in release from the baseline
+# commit, the interpreter looking up every name, and from the current tree. The criterion
+# benchmarks can't run on the baseline, its API is different. The sizes are bigger than in the
+# benchmarks so a run takes long enough to be measured, the best of 3 runs is kept.
+set -e
+
+BASELINE=6f3ef17
+SIZES="numeric:2000 arrays:1000 strings:500 large_array:60"
+
+root=$(git rev-parse --show-toplevel)
+programs="$root/topflight_core/benches/programs"
+work=$(mktemp -d)
+git -C "$root" worktree add --quiet --detach "$work/baseline" "$BASELINE"
+trap 'git -C "$root" worktree remove --force "$work/baseline"; rm -rf "$work"' EXIT
+
+CARGO_TARGET_DIR="$work/baseline_target" cargo build --quiet --release -p topflight \
+	--manifest-path "$work/baseline/Cargo.toml"
+cargo build --quiet --release -p topflight --manifest-path "$root/Cargo.toml"
+baseline="$work/baseline_target/release/topflight"
+current="$root/target/release/topflight"
+
+# Best of 3, in seconds
+best_time() {
+	local best=""
+	for _ in 1 2 3; do
+		local start end
+		start=$(date +%s%N)
+		"$1" "$2" >/dev/null
+		end=$(date +%s%N)
+		local time=$(((end - start) / 1000000))
+		if [ -z "$best" ] || [ "$time" -lt "$best" ]; then best=$time; fi
+	done
+	printf "%d.%03d" $((best / 1000)) $((best % 1000))
+}
+
+printf "| %-11s | %-4s | %-8s | %-7s |\n" program size baseline current
+printf "|-------------|------|----------|---------|\n"
+for entry in $SIZES; do
+	name=${entry%%:*}
+	size=${entry##*:}
+	sed "s/STORE size INTEGER(60)/STORE size INTEGER($size)/" "$programs/$name.tpf" >"$work/$name.tpf"
+	printf "| %-11s | %4s | %7ss | %6ss |\n" "$name" "$size" \
+		"$(best_time "$baseline" "$work/$name.tpf")" "$(best_time "$current" "$work/$name.tpf")"
+done
@@ -0,0 +1,36 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use topflight_core::Interpreter;
+
+// The programs are in `programs`, `compare_with_baseline.sh` runs them with the interpreter from
+// before the bytecode, see the README for the results. Loops are written as recursive routines,
+// the loops are nested to keep the recursion shallow.
+const NUMERIC: &str = include_str!("programs/numeric.tpf");
+const ARRAYS: &str = include_str!("programs/arrays.tpf");
+const STRINGS: &str = include_str!("programs/strings.tpf");
+// The array is big so any copy of it would dominate
+const LARGE_ARRAY: &str = include_str!("programs/large_array.tpf");
+
+fn run(source: &str) -> String {
+    let mut interpreter = Interpreter::default();
+    let program = interpreter
+        .load_source(source)
+        .expect("The benchmark is valid");
+
+    let mut output = String::new();
+    for (_, instruction) in program {
+        interpreter
+            .execute(&instruction, &mut output)
+            .expect("The benchmark is valid");
+    }
+    output
+}
+
+fn benchmarks(c: &mut Criterion) {
+    c.bench_function("numeric", |b| b.iter(|| run(black_box(NUMERIC))));
+    c.bench_function("arrays", |b| b.iter(|| run(black_box(ARRAYS))));
+    c.bench_function("strings", |b| b.iter(|| run(black_box(STRINGS))));
+    c.bench_function("large_array", |b| b.iter(|| run(black_box(LARGE_ARRAY))));
+}
+
+criterion_group!(benches, benchmarks);
+criterion_main!(benches);
@@ -0,0 +1,713 @@
+use std::collections::{HashMap, HashSet};
+use thiserror::Error;
+use topflight_definitions::*;
+
+use crate::{parse_line, Line};
+
+#[derive(Error, Debug)]
+pub enum Problem {
+    #[error("{0}")]
+    InvalidLine(crate::Error),
+    #[error("Routine `{0}` is called but never defined")]
+    UndefinedRoutine(String),
+    #[error("Variable `{0}` is read but never stored")]
+    UndefinedVariable(String),
+    #[error("Variable `{0}` is freed but never stored")]
+    FreeOfUnknownVariable(String),
+    #[error("Routine `{0}` is never closed")]
+    UnterminatedRoutine(String),
+    #[error("Routine `{0}` is never called")]
+    UnusedRoutine(String),
+    #[error("`{instruction}` expects {expected} but `{variable}` is of type `{found}`")]
+    UnexpectedType {
+        instruction: &'static str,
+        variable: String,
+        expected: &'static str,
+        found: &'static str,
+    },
+    #[error("`{instruction}` expects values of the same type but `{variable_a}` is of type `{type_a}` and `{variable_b}` is of type `{type_b}`")]
+    MismatchingTypes {
+        instruction: &'static str,
+        variable_a: String,
+        type_a: &'static str,
+        variable_b: String,
+        type_b: &'static str,
+    },
+    #[error("`{instruction}` cannot put a value of type `{found}` in `{array}` which is of type `{array_type}`")]
+    UnexpectedElementType {
+        instruction: &'static str,
+        array: String,
+        array_type: &'static str,
+        found: &'static str,
+    },
+}
+
+impl Problem {
+    // An unused routine does not prevent the program from running
+    pub fn is_error(&self) -> bool {
+        !matches!(self, Problem::UnusedRoutine(_))
+    }
+}
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub line_number: usize,
+    pub problem: Problem,
+}
+
+// Parse the whole program without executing it and report everything that looks wrong
+pub fn check(source: &str) -> Vec<Diagnostic> {
+    let mut checker = Checker::default();
+    checker.parse(source);
+    checker.check_names();
+    checker.check_types();
+    checker
+        .diagnostics
+        .sort_by_key(|diagnostic| diagnostic.line_number);
+    checker.diagnostics
+}
+
+struct LocatedInstruction {
+    line_number: usize,
+    routine: Option<String>,
+    instruction: Instruction,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum InferredType {
+    Known(&'static str),
+    // The variable can hold several types depending on the path taken
+    Conflicting,
+}
+
+#[derive(Default)]
+struct Checker {
+    instructions: Vec<LocatedInstruction>,
+    // Routine name and the line where it is defined
+    routines: HashMap<String, usize>,
+    // The imported files are not read, so their routines are unknown
+    imported_namespaces: Vec<String>,
+    has_import_without_namespace: bool,
+    types: HashMap<String, InferredType>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Checker {
+    fn report(&mut self, line_number: usize, problem: Problem) {
+        self.diagnostics.push(Diagnostic {
+            line_number,
+            problem,
+        });
+    }
+
+    fn parse(&mut self, source: &str) {
+        let mut current_routine: Option<(String, usize)> = None;
+        for (i, line) in source.lines().enumerate() {
+            let line_number = i + 1;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match parse_line(line) {
+                Err(error) => self.report(line_number, Problem::InvalidLine(error)),
+                Ok(Line::RoutineStart(routine_name)) => match &current_routine {
+                    None => current_routine = Some((routine_name, line_number)),
+                    Some((current_name, _)) => {
+                        let error =
+                            crate::Error::SubRoutineFound(current_name.clone(), routine_name);
+                        self.report(line_number, Problem::InvalidLine(error));
+                    }
+                },
+                Ok(Line::RoutineEnd(routine_name)) => match current_routine.take() {
+                    None => {
+                        let error = crate::Error::UnexpectedEndSubroutine(routine_name);
+                        self.report(line_number, Problem::InvalidLine(error));
+                    }
+                    Some((current_name, start)) if current_name == routine_name => {
+                        if let Some(first_start) = self.routines.get(&current_name) {
+                            let error =
+                                crate::Error::RoutineDefinedTwice(current_name, *first_start);
+                            self.report(line_number, Problem::InvalidLine(error));
+                        } else {
+                            self.routines.insert(current_name, start);
+                        }
+                    }
+                    Some((current_name, start)) => {
+                        let error = crate::Error::MismatchingEndSubroutine(
+                            current_name.clone(),
+                            routine_name,
+                        );
+                        self.report(line_number, Problem::InvalidLine(error));
+                        current_routine = Some((current_name, start));
+                    }
+                },
+                Ok(Line::Import(import)) => match (&current_routine, import.namespace) {
+                    (Some((current_name, _)), _) => {
+                        let error = crate::Error::ImportInRoutine(current_name.clone());
+                        self.report(line_number, Problem::InvalidLine(error));
+                    }
+                    (None, None) => self.has_import_without_namespace = true,
+                    (None, Some(namespace)) => self.imported_namespaces.push(namespace),
+                },
+                Ok(Line::Instruction(instruction)) => self.instructions.push(LocatedInstruction {
+                    line_number,
+                    routine: current_routine.as_ref().map(|(name, _)| name.clone()),
+                    instruction,
+                }),
+            }
+        }
+
+        if let Some((routine_name, start)) = current_routine {
+            self.report(start, Problem::UnterminatedRoutine(routine_name));
+        }
+    }
+
+    fn check_names(&mut self) {
+        let mut stored: HashSet<&str> = HashSet::from(["args"]);
+        for located in self.instructions.iter() {
+            stored.extend(located.instruction.written_variables());
+        }
+
+        let mut problems = Vec::new();
+        let mut called = HashSet::new();
+        for located in self.instructions.iter() {
+            let instruction = &located.instruction;
+            for variable in instruction.read_variables() {
+                if stored.contains(variable) {
+                    continue;
+                }
+                let problem = match instruction {
+                    Instruction::Free(_) => Problem::FreeOfUnknownVariable(variable.to_string()),
+                    _ => Problem::UndefinedVariable(variable.to_string()),
+                };
+                problems.push((located.line_number, problem));
+            }
+
+            if let Some(routine_name) = instruction.called_routine() {
+                if !self.routines.contains_key(routine_name) && !self.may_be_imported(routine_name)
+                {
+                    let problem = Problem::UndefinedRoutine(routine_name.to_string());
+                    problems.push((located.line_number, problem));
+                }
+                // A routine calling itself is not enough to be used
+                if located.routine.as_deref() != Some(routine_name) {
+                    called.insert(routine_name.to_string());
+                }
+            }
+        }
+
+        for (routine_name, line_number) in self.routines.iter() {
+            // The tests are called by `topflight test`
+            if !called.contains(routine_name) && !routine_name.starts_with("test_") {
+                problems.push((*line_number, Problem::UnusedRoutine(routine_name.clone())));
+            }
+        }
+
+        for (line_number, problem) in problems {
+            self.report(line_number, problem);
+        }
+    }
+
+    fn may_be_imported(&self, routine_name: &str) -> bool {
+        self.has_import_without_namespace
+            || self.imported_namespaces.iter().any(|namespace| {
+                routine_name
+                    .strip_prefix(namespace.as_str())
+                    .is_some_and(|name| name.starts_with("::"))
+            })
+    }
+
+    fn type_of(&self, variable: &str) -> Option<&'static str> {
+        match self.types.get(variable) {
+            Some(InferredType::Known(type_name)) => Some(type_name),
+            _ => None,
+        }
+    }
+
+    // Type of the value stored by the instruction, None if it can not be guessed
+    fn produced_type(&self, instruction: &Instruction) -> Option<InferredType> {
+        let known = |variable: &str| self.types.get(variable).copied();
+        match instruction {
+            Instruction::Store(Store { value, .. }) => Some(InferredType::Known(value.type_name())),
+            Instruction::Copy(Copy { input, .. }) => known(input),
+            Instruction::Add(Add { input_a, .. })
+            | Instruction::Substract(Substract { input_a, .. })
+            | Instruction::Multiply(Multiply { input_a, .. })
+            | Instruction::Divide(Divide { input_a, .. }) => known(input_a),
+            Instruction::Modulo(_) | Instruction::Size(_) | Instruction::IndexOf(_) => {
+                Some(InferredType::Known("INTEGER"))
+            }
+            Instruction::LogicalAnd(_)
+            | Instruction::LogicalOr(_)
+            | Instruction::LogicalNot(_)
+            | Instruction::CompareEqual(_)
+            | Instruction::CompareDifferent(_)
+            | Instruction::CompareLess(_)
+            | Instruction::CompareLessOrEqual(_)
+            | Instruction::CompareGreater(_)
+            | Instruction::CompareGreaterOrEqual(_)
+            | Instruction::IsDefined(_)
+            | Instruction::IsArray(_)
+            | Instruction::Contains(_) => Some(InferredType::Known("BOOLEAN")),
+            Instruction::TypeOf(_) => Some(InferredType::Known("STRING")),
+            Instruction::Concat(Concat { input_a, .. }) => known(input_a),
+            Instruction::Slice(Slice { array_input, .. }) => known(array_input),
+            Instruction::Range(_) => Some(InferredType::Known("ARRAY_OF_INTEGER")),
+            Instruction::GetAt(GetAt { array_input, .. }) => match known(array_input)? {
+                InferredType::Known(array_type) => match element_type(array_type) {
+                    Some(element_type) => Some(InferredType::Known(element_type)),
+                    None => Some(InferredType::Conflicting),
+                },
+                InferredType::Conflicting => Some(InferredType::Conflicting),
+            },
+            // The other instructions modify an array in place, so they do not change its type
+            _ => None,
+        }
+    }
+
+    fn infer_types(&mut self) {
+        self.types
+            .insert(String::from("args"), InferredType::Known("ARRAY_OF_STRING"));
+
+        // Each variable can only go from unknown to known to conflicting, so this ends
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for located in self.instructions.iter() {
+                let produced = match self.produced_type(&located.instruction) {
+                    Some(produced) => produced,
+                    None => continue,
+                };
+                for variable in located.instruction.written_variables() {
+                    let merged = match self.types.get(variable) {
+                        None => produced,
+                        Some(current) if *current == produced => continue,
+                        Some(_) => InferredType::Conflicting,
+                    };
+                    if self.types.get(variable) != Some(&merged) {
+                        self.types.insert(variable.to_string(), merged);
+                        changed = true;
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_types(&mut self) {
+        self.infer_types();
+
+        let mut problems = Vec::new();
+        for located in self.instructions.iter() {
+            for problem in self.instruction_type_problems(&located.instruction) {
+                problems.push((located.line_number, problem));
+            }
+        }
+
+        for (line_number, problem) in problems {
+            self.report(line_number, problem);
+        }
+    }
+
+    fn instruction_type_problems(&self, instruction: &Instruction) -> Vec<Problem> {
+        let name = instruction.name();
+        let mut problems = Vec::new();
+        let mut expect =
+            |variable: &str, expected: &'static str, accepted: &dyn Fn(&str) -> bool| {
+                if let Some(found) = self.type_of(variable) {
+                    if !accepted(found) {
+                        problems.push(Problem::UnexpectedType {
+                            instruction: name,
+                            variable: variable.to_string(),
+                            expected,
+                            found,
+                        });
+                    }
+                }
+            };
+        let is_arithmetic = |t: &str| t == "INTEGER" || t == "NUMBER";
+        let is_integer = |t: &str| t == "INTEGER";
+        let is_boolean = |t: &str| t == "BOOLEAN";
+        let is_string = |t: &str| t == "STRING";
+        let is_array = |t: &str| element_type(t).is_some();
+
+        match instruction {
+            Instruction::Add(Add {
+                input_a, input_b, ..
+            })
+            | Instruction::Substract(Substract {
+                input_a, input_b, ..
+            })
+            | Instruction::Multiply(Multiply {
+                input_a, input_b, ..
+            })
+            | Instruction::Divide(Divide {
+                input_a, input_b, ..
+            }) => {
+                expect(input_a, "an arithmetic type", &is_arithmetic);
+                expect(input_b, "an arithmetic type", &is_arithmetic);
+                // Only compare the operands once they are both valid on their own
+                let both_arithmetic = [input_a, input_b]
+                    .iter()
+                    .all(|input| self.type_of(input).is_none_or(is_arithmetic));
+                if both_arithmetic {
+                    problems.extend(self.mismatching_types(name, input_a, input_b));
+                }
+            }
+            Instruction::Modulo(Modulo {
+                input_a, input_b, ..
+            }) => {
+                expect(input_a, "an `INTEGER`", &is_integer);
+                expect(input_b, "an `INTEGER`", &is_integer);
+            }
+            Instruction::LogicalAnd(LogicalAnd {
+                input_a, input_b, ..
+            })
+            | Instruction::LogicalOr(LogicalOr {
+                input_a, input_b, ..
+            }) => {
+                expect(input_a, "a `BOOLEAN`", &is_boolean);
+                expect(input_b, "a `BOOLEAN`", &is_boolean);
+            }
+            Instruction::LogicalNot(LogicalNot { input, .. }) => {
+                expect(input, "a `BOOLEAN`", &is_boolean);
+            }
+            Instruction::CallIf(CallIf {
+                boolean_input_name, ..
+            }) => {
+                expect(boolean_input_name, "a `BOOLEAN`", &is_boolean);
+            }
+            Instruction::Assert(Assert { input }) => {
+                expect(input, "a `BOOLEAN`", &is_boolean);
+            }
+            Instruction::CompareEqual(CompareEqual {
+                input_a, input_b, ..
+            })
+            | Instruction::CompareDifferent(CompareDifferent {
+                input_a, input_b, ..
+            })
+            | Instruction::CompareLess(CompareLess {
+                input_a, input_b, ..
+            })
+            | Instruction::CompareLessOrEqual(CompareLessOrEqual {
+                input_a, input_b, ..
+            })
+            | Instruction::CompareGreater(CompareGreater {
+                input_a, input_b, ..
+            })
+            | Instruction::CompareGreaterOrEqual(CompareGreaterOrEqual {
+                input_a, input_b, ..
+            }) => {
+                problems.extend(self.mismatching_types(name, input_a, input_b));
+            }
+            Instruction::Concat(Concat {
+                input_a, input_b, ..
+            }) => {
+                let is_concatenable = |t: &str| is_string(t) || is_array(t);
+                expect(input_a, "a `STRING` or an array", &is_concatenable);
+                expect(input_b, "a `STRING` or an array", &is_concatenable);
+                let both_concatenable = [input_a, input_b]
+                    .iter()
+                    .all(|input| self.type_of(input).is_none_or(is_concatenable));
+                if both_concatenable {
+                    problems.extend(self.mismatching_types(name, input_a, input_b));
+                }
+            }
+            Instruction::Sort(Sort { array_output })
+            | Instruction::Reverse(Reverse { array_output }) => {
+                expect(array_output, "an array", &is_array);
+            }
+            Instruction::IndexOf(IndexOf { array_input, .. })
+            | Instruction::Contains(Contains { array_input, .. }) => {
+                expect(array_input, "an array", &is_array);
+            }
+            Instruction::Slice(Slice {
+                array_input,
+                start,
+                end,
+                ..
+            }) => {
+                expect(array_input, "an array", &is_array);
+                expect(start, "an `INTEGER`", &is_integer);
+                expect(end, "an `INTEGER`", &is_integer);
+            }
+            Instruction::Range(Range { start, end, .. }) => {
+                expect(start, "an `INTEGER`", &is_integer);
+                expect(end, "an `INTEGER`", &is_integer);
+            }
+            Instruction::GetAt(GetAt {
+                array_input, index, ..
+            })
+            | Instruction::Erase(Erase { array_input, index }) => {
+                expect(array_input, "an array", &is_array);
+                expect(index, "an `INTEGER`", &is_integer);
+            }
+            Instruction::Resize(Resize {
+                array_input,
+                new_size,
+            }) => {
+                expect(array_input, "an array", &is_array);
+                expect(new_size, "an `INTEGER`", &is_integer);
+            }
+            Instruction::Size(Size { array_input, .. }) => {
+                expect(array_input, "an array", &is_array);
+            }
+            Instruction::StoreAt(StoreAt {
+                array_output,
+                index,
+                value,
+            }) => {
+                expect(array_output, "an array", &is_array);
+                expect(index, "an `INTEGER`", &is_integer);
+                problems.extend(self.unexpected_element_type(
+                    name,
+                    array_output,
+                    Some(value.type_name()),
+                ));
+            }
+            Instruction::CopyAt(CopyAt {
+                array_output,
+                index,
+                input,
+            })
+            | Instruction::Insert(Insert {
+                array_output,
+                index,
+                input,
+            }) => {
+                expect(array_output, "an array", &is_array);
+                expect(index, "an `INTEGER`", &is_integer);
+                problems.extend(self.unexpected_element_type(
+                    name,
+                    array_output,
+                    self.type_of(input),
+                ));
+            }
+            Instruction::PushBack(PushBack {
+                array_output,
+                input,
+            })
+            | Instruction::Fill(Fill {
+                array_output,
+                input,
+            }) => {
+                expect(array_output, "an array", &is_array);
+                problems.extend(self.unexpected_element_type(
+                    name,
+                    array_output,
+                    self.type_of(input),
+                ));
+            }
+            _ => (),
+        }
+        problems
+    }
+
+    fn mismatching_types(
+        &self,
+        instruction: &'static str,
+        variable_a: &str,
+        variable_b: &str,
+    ) -> Option<Problem> {
+        let type_a = self.type_of(variable_a)?;
+        let type_b = self.type_of(variable_b)?;
+        if type_a == type_b {
+            return None;
+        }
+        Some(Problem::MismatchingTypes {
+            instruction,
+            variable_a: variable_a.to_string(),
+            type_a,
+            variable_b: variable_b.to_string(),
+            type_b,
+        })
+    }
+
+    fn unexpected_element_type(
+        &self,
+        instruction: &'static str,
+        array: &str,
+        found: Option<&'static str>,
+    ) -> Option<Problem> {
+        let array_type = self.type_of(array)?;
+        let found = found?;
+        // Not being an array at all is already reported
+        let expected = element_type(array_type)?;
+        if expected == found {
+            return None;
+        }
+        Some(Problem::UnexpectedElementType {
+            instruction,
+            array: array.to_string(),
+            array_type,
+            found,
+        })
+    }
+}
+
+fn element_type(array_type: &str) -> Option<&'static str> {
+    match array_type {
+        "ARRAY_OF_INTEGER" => Some("INTEGER"),
+        "ARRAY_OF_NUMBER" => Some("NUMBER"),
+        "ARRAY_OF_STRING" => Some("STRING"),
+        "ARRAY_OF_BOOLEAN" => Some("BOOLEAN"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(source: &str) -> Vec<(usize, String)> {
+        check(source)
+            .into_iter()
+            .map(|diagnostic| (diagnostic.line_number, diagnostic.problem.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_program() {
+        let source = "STORE i INTEGER(0)\n<print_i>\nPRINT i\n</print_i>\nCALL print_i\n";
+        assert!(check(source).is_empty());
+    }
+
+    #[test]
+    fn test_names() {
+        let source = "PRINT nope\nFREE nope_again\nCALL missing\n<unused>\n</unused>\n<open>\n";
+        assert_eq!(
+            problems(source),
+            vec![
+                (1, String::from("Variable `nope` is read but never stored")),
+                (
+                    2,
+                    String::from("Variable `nope_again` is freed but never stored")
+                ),
+                (
+                    3,
+                    String::from("Routine `missing` is called but never defined")
+                ),
+                (4, String::from("Routine `unused` is never called")),
+                (6, String::from("Routine `open` is never closed")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_types() {
+        let source = "STORE s STRING(\"a\")\nSTORE i INTEGER(1)\nSTORE n NUMBER(1.5)\nCOPY i j\nADD s j k\nADD n j k\nPUSH_BACK s i\n";
+        assert_eq!(
+            problems(source),
+            vec![
+                (
+                    5,
+                    String::from("`ADD` expects an arithmetic type but `s` is of type `STRING`")
+                ),
+                (
+                    6,
+                    String::from("`ADD` expects values of the same type but `n` is of type `NUMBER` and `j` is of type `INTEGER`")
+                ),
+                (
+                    7,
+                    String::from("`PUSH_BACK` expects an array but `s` is of type `STRING`")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_type_instructions() {
+        // IS_DEFINED does not read its variable and the results have a known type
+        let source = "IS_DEFINED maybe defined\nCALL_IF print_type defined\n<print_type>\nTYPE_OF maybe type\nIS_ARRAY maybe array\nADD type array result\n</print_type>\n";
+        assert_eq!(
+            problems(source),
+            vec![
+                (4, String::from("Variable `maybe` is read but never stored")),
+                (5, String::from("Variable `maybe` is read but never stored")),
+                (
+                    6,
+                    String::from("`ADD` expects an arithmetic type but `type` is of type `STRING`")
+                ),
+                (
+                    6,
+                    String::from(
+                        "`ADD` expects an arithmetic type but `array` is of type `BOOLEAN`"
+                    )
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_array_instructions() {
+        let source = "STORE a ARRAY_OF_INTEGER(1)\nSTORE s STRING(\"s\")\nCONCAT a a b\nSLICE b s s c\nCONCAT c s d\nRANGE s s e\n";
+        assert_eq!(
+            problems(source),
+            vec![
+                (
+                    4,
+                    String::from("`SLICE` expects an `INTEGER` but `s` is of type `STRING`")
+                ),
+                (
+                    4,
+                    String::from("`SLICE` expects an `INTEGER` but `s` is of type `STRING`")
+                ),
+                (
+                    5,
+                    String::from("`CONCAT` expects values of the same type but `c` is of type `ARRAY_OF_INTEGER` and `s` is of type `STRING`")
+                ),
+                (
+                    6,
+                    String::from("`RANGE` expects an `INTEGER` but `s` is of type `STRING`")
+                ),
+                (
+                    6,
+                    String::from("`RANGE` expects an `INTEGER` but `s` is of type `STRING`")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_routine_defined_twice() {
+        let source = "<r>\n</r>\nCALL r\n<r>\n</r>\n";
+        assert_eq!(
+            problems(source),
+            vec![(
+                5,
+                String::from(
+                    "Routine `r` is already defined at line 1, a file can only define it once"
+                )
+            )]
+        );
+    }
+
+    #[test]
+    fn test_imported_routines() {
+        let source = "IMPORT strings.tpf AS strings\nCALL strings::insert\nCALL other::insert\n";
+        assert_eq!(
+            problems(source),
+            vec![(
+                3,
+                String::from("Routine `other::insert` is called but never defined")
+            )]
+        );
+    }
+
+    #[test]
+    fn test_conflicting_types_are_not_reported() {
+        let source = "STORE a INTEGER(1)\nSTORE a STRING(\"a\")\nADD a a b\n";
+        assert!(check(source).is_empty());
+    }
+
+    #[test]
+    fn test_tests_are_used() {
+        let source = "<test_one>\nSTORE i INTEGER(1)\nASSERT i\n</test_one>\n";
+        assert_eq!(
+            problems(source),
+            vec![(
+                3,
+                String::from("`ASSERT` expects a `BOOLEAN` but `i` is of type `INTEGER`")
+            )]
+        );
+    }
+}
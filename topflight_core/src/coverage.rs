@@ -0,0 +1,254 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use topflight_definitions::{CallIf, Instruction};
+use topflight_vm::{Event, Memory, Observer};
+
+use crate::{Interpreter, Value};
+
+// Records the lines and routines executed by a program, and which way each CALL_IF went
+pub struct Coverage {
+    // Execution count of every instruction line of the program
+    lines: BTreeMap<usize, u64>,
+    // Routines defined in the program with the line where they start and their number of calls
+    routines: Vec<(String, usize, u64)>,
+    routine_indices: HashMap<String, usize>,
+    // The imported routines are not measured, their lines are in other files
+    imported: Vec<String>,
+    // Number of calls and of skips of each CALL_IF line
+    conditions: BTreeMap<usize, (u64, u64)>,
+}
+
+impl Coverage {
+    // Takes the top level instructions with their line and the interpreter which loaded them
+    pub fn new(program: &[(usize, Instruction)], interpreter: &Interpreter) -> Coverage {
+        let mut coverage = Coverage {
+            lines: BTreeMap::new(),
+            routines: Vec::new(),
+            routine_indices: HashMap::new(),
+            imported: Vec::new(),
+            conditions: BTreeMap::new(),
+        };
+
+        let routines = &interpreter.routines;
+        let mut names: Vec<&String> = routines.keys().collect();
+        names.sort();
+        for name in names {
+            let start = match interpreter.declared_routines().get(name) {
+                Some(start) => *start,
+                None => {
+                    coverage.imported.push(name.clone());
+                    continue;
+                }
+            };
+            let routine = routines
+                .get(name)
+                .expect("The name comes from the routines");
+            coverage
+                .routine_indices
+                .insert(name.clone(), coverage.routines.len());
+            coverage.routines.push((name.clone(), start, 0));
+            coverage.add_lines(routine.lines.iter().copied().zip(&routine.instructions));
+        }
+        coverage.add_lines(
+            program
+                .iter()
+                .map(|(line, instruction)| (*line, instruction)),
+        );
+        coverage
+    }
+
+    // Routines of the program never called, sorted by name
+    pub fn never_called(&self) -> Vec<&str> {
+        self.routines
+            .iter()
+            .filter(|(_, _, calls)| *calls == 0)
+            .map(|(name, _, _)| name.as_str())
+            .collect()
+    }
+
+    // Routines imported from other files, not measured, sorted by name
+    pub fn imported(&self) -> &[String] {
+        &self.imported
+    }
+
+    pub fn executed_lines(&self) -> usize {
+        self.lines.values().filter(|count| **count > 0).count()
+    }
+
+    pub fn total_lines(&self) -> usize {
+        self.lines.len()
+    }
+
+    // Report in the lcov format, for the file at this path
+    pub fn lcov(&self, source_file: &str) -> String {
+        let mut report = format!("TN:\nSF:{}\n", source_file);
+        for (name, line, _) in self.routines.iter() {
+            let _ = writeln!(report, "FN:{},{}", line, name);
+        }
+        for (name, _, calls) in self.routines.iter() {
+            let _ = writeln!(report, "FNDA:{},{}", calls, name);
+        }
+        let called = self.routines.len() - self.never_called().len();
+        let _ = writeln!(report, "FNF:{}\nFNH:{}", self.routines.len(), called);
+
+        // Each CALL_IF has two branches: the call and the skip
+        let mut taken_branches = 0;
+        for (line, (calls, skips)) in self.conditions.iter() {
+            let _ = writeln!(report, "BRDA:{},0,0,{}", line, calls);
+            let _ = writeln!(report, "BRDA:{},0,1,{}", line, skips);
+            taken_branches += (*calls > 0) as usize + (*skips > 0) as usize;
+        }
+        let _ = writeln!(
+            report,
+            "BRF:{}\nBRH:{}",
+            self.conditions.len() * 2,
+            taken_branches
+        );
+
+        for (line, count) in self.lines.iter() {
+            let _ = writeln!(report, "DA:{},{}", line, count);
+        }
+        let _ = writeln!(
+            report,
+            "LF:{}\nLH:{}",
+            self.total_lines(),
+            self.executed_lines()
+        );
+        report += "end_of_record\n";
+        report
+    }
+
+    fn add_lines<'a>(&mut self, lines: impl Iterator<Item = (usize, &'a Instruction)>) {
+        for (line, instruction) in lines {
+            self.lines.insert(line, 0);
+            if let Instruction::CallIf(_) = instruction {
+                self.conditions.insert(line, (0, 0));
+            }
+        }
+    }
+}
+
+impl Observer for Coverage {
+    // A line is executed even if its instruction fails
+    fn before(&mut self, event: &Event, _memory: &Memory) {
+        if let Some(count) = self.lines.get_mut(&event.line) {
+            *count += 1;
+        }
+    }
+
+    // The calls and the branches are counted once they succeeded, where they are called since an
+    // empty routine has no instruction of its own
+    fn after(&mut self, event: &Event, memory: &Memory) {
+        let called = match event.instruction {
+            Instruction::CallIf(CallIf {
+                boolean_input_name, ..
+            }) => {
+                let condition = memory.load(boolean_input_name).ok();
+                match (self.conditions.get_mut(&event.line), condition) {
+                    (Some((calls, _)), Some(Value::Boolean(true))) => *calls += 1,
+                    (Some((_, skips)), Some(Value::Boolean(false))) => *skips += 1,
+                    _ => (),
+                }
+                matches!(condition, Some(Value::Boolean(true)))
+            }
+            instruction => instruction.called_routine().is_some(),
+        };
+        let index = event
+            .instruction
+            .called_routine()
+            .and_then(|routine| self.routine_indices.get(routine));
+        if let (true, Some(index)) = (called, index) {
+            self.routines[*index].2 += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VirtualFiles;
+
+    #[test]
+    fn test_coverage() {
+        let mut virtual_files = VirtualFiles::default();
+        virtual_files.add(
+            "lib.tpf",
+            String::from("<imported>\nPRINT i\n</imported>\n"),
+        );
+        let mut interpreter = Interpreter::default();
+        interpreter.set_file_loader(virtual_files);
+        let source = "<count>
+ADD i one i
+COMPARE_LESS i end keep_going
+CALL_IF count keep_going
+</count>
+<unused>
+PRINT i
+</unused>
+<empty>
+</empty>
+IMPORT lib.tpf
+STORE i INTEGER(0)
+STORE one INTEGER(1)
+STORE end INTEGER(3)
+CALL count
+CALL empty
+";
+        let program = interpreter
+            .load_source(source)
+            .expect("The lines are valid");
+        let mut coverage = Coverage::new(&program, &interpreter);
+        let mut output = String::new();
+        for (line, instruction) in program {
+            interpreter
+                .execute_observed(&instruction, line, &mut output, &mut coverage)
+                .expect("The program is valid");
+        }
+
+        assert_eq!(coverage.never_called(), vec!["unused"]);
+        assert_eq!(coverage.imported(), ["imported"]);
+        assert_eq!((coverage.executed_lines(), coverage.total_lines()), (8, 9));
+        let lcov = coverage.lcov("count.tpf");
+        for expected in [
+            "SF:count.tpf\n",
+            "FN:1,count\nFN:9,empty\nFN:6,unused\n",
+            "FNDA:3,count\nFNDA:1,empty\nFNDA:0,unused\n",
+            "FNF:3\nFNH:2\n",
+            "BRDA:4,0,0,2\nBRDA:4,0,1,1\n",
+            "BRF:2\nBRH:2\n",
+            "DA:2,3\n",
+            "DA:7,0\n",
+            "LF:9\nLH:8\nend_of_record\n",
+        ] {
+            assert!(lcov.contains(expected), "{} not in\n{}", expected, lcov);
+        }
+    }
+
+    #[test]
+    fn test_failed_calls_are_not_counted() {
+        let mut interpreter = Interpreter::default();
+        let source = "<deep>
+CALL deep
+</deep>
+<choose>
+</choose>
+STORE text STRING(\"not a boolean\")
+CALL deep
+CALL_IF choose text
+";
+        let program = interpreter.load_source(source).unwrap();
+        let mut coverage = Coverage::new(&program, &interpreter);
+        interpreter.limits.max_call_depth = Some(3);
+        let mut output = String::new();
+        for (line, instruction) in program {
+            let result =
+                interpreter.execute_observed(&instruction, line, &mut output, &mut coverage);
+            assert_eq!(result.is_err(), line != 6);
+        }
+
+        // Only the calls which did not go over the maximum call depth
+        let lcov = coverage.lcov("failed.tpf");
+        assert!(lcov.contains("FNDA:0,choose\nFNDA:3,deep\n"), "{}", lcov);
+        assert!(lcov.contains("BRDA:8,0,0,0\nBRDA:8,0,1,0\n"), "{}", lcov);
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use topflight_definitions::{AssertError, Call, CallIf, Instruction};
+
+use crate::{Routine, Routines};
+
+// Where the files used by IMPORT are read from
+pub trait FileLoader {
+    fn read(&self, path: &Path) -> io::Result<String>;
+}
+
+#[derive(Default)]
+pub struct FileSystem;
+
+impl FileLoader for FileSystem {
+    fn read(&self, path: &Path) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+}
+
+// Files kept in memory, for example when there is no file system like in the browser
+#[derive(Default, Clone)]
+pub struct VirtualFiles {
+    files: HashMap<PathBuf, String>,
+}
+
+impl VirtualFiles {
+    pub fn add(&mut self, path: &str, source: String) {
+        self.files.insert(normalize(Path::new(path)), source);
+    }
+}
+
+impl FileLoader for VirtualFiles {
+    fn read(&self, path: &Path) -> io::Result<String> {
+        match self.files.get(&normalize(path)) {
+            Some(source) => Ok(source.clone()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "file not found")),
+        }
+    }
+}
+
+pub(crate) struct Import {
+    pub path: String,
+    pub namespace: Option<String>,
+}
+
+// IMPORT path.tpf
+// IMPORT path.tpf AS namespace
+pub(crate) fn parse_import(str: &str) -> Option<Import> {
+    let words: Vec<&str> = str.split(' ').collect();
+    match words.as_slice() {
+        ["IMPORT", path] if !path.is_empty() => Some(Import {
+            path: path.to_string(),
+            namespace: None,
+        }),
+        ["IMPORT", path, "AS", namespace] if !path.is_empty() && !namespace.is_empty() => {
+            Some(Import {
+                path: path.to_string(),
+                namespace: Some(namespace.to_string()),
+            })
+        }
+        _ => None,
+    }
+}
+
+// Resolve `.` and `..` without touching the file system, so the same file always has the same path
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                Some(Component::RootDir) => (),
+                _ => normalized.push(".."),
+            },
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+pub(crate) fn namespaced(namespace: &str, name: &str) -> String {
+    format!("{}::{}", namespace, name)
+}
+
+// Prefix the routines with the namespace, calls between them are updated too
+pub(crate) fn add_namespace(routines: Routines, namespace: &str) -> Routines {
+    let names: HashSet<String> = routines.keys().cloned().collect();
+    let prefixed = |name: &str| namespaced(namespace, name);
+
+    routines
+        .into_values()
+        .map(|routine| {
+            let instructions = routine
+                .instructions
+                .into_iter()
+                .map(|instruction| match instruction {
+                    Instruction::Call(Call { routine_name }) if names.contains(&routine_name) => {
+                        Instruction::Call(Call {
+                            routine_name: prefixed(&routine_name),
+                        })
+                    }
+                    Instruction::CallIf(CallIf {
+                        routine_name,
+                        boolean_input_name,
+                    }) if names.contains(&routine_name) => Instruction::CallIf(CallIf {
+                        routine_name: prefixed(&routine_name),
+                        boolean_input_name,
+                    }),
+                    Instruction::AssertError(AssertError { routine_name })
+                        if names.contains(&routine_name) =>
+                    {
+                        Instruction::AssertError(AssertError {
+                            routine_name: prefixed(&routine_name),
+                        })
+                    }
+                    instruction => instruction,
+                })
+                .collect();
+            let name = prefixed(&routine.name);
+            let lines = routine.lines;
+            (
+                name.clone(),
+                Routine {
+                    name,
+                    instructions,
+                    lines,
+                },
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_import() {
+        let import = parse_import("IMPORT strings.tpf").expect("Valid import");
+        assert_eq!(import.path, "strings.tpf");
+        assert!(import.namespace.is_none());
+
+        let import = parse_import("IMPORT ../lib/strings.tpf AS strings").expect("Valid import");
+        assert_eq!(import.path, "../lib/strings.tpf");
+        assert_eq!(import.namespace.as_deref(), Some("strings"));
+
+        assert!(parse_import("IMPORT").is_none());
+        assert!(parse_import("IMPORT a.tpf AS").is_none());
+        assert!(parse_import("IMPORT a.tpf b.tpf").is_none());
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize(Path::new("a/./b/../c.tpf")), Path::new("a/c.tpf"));
+        assert_eq!(
+            normalize(Path::new("../a/../../b.tpf")),
+            Path::new("../../b.tpf")
+        );
+        assert_eq!(normalize(Path::new("/../a.tpf")), Path::new("/a.tpf"));
+    }
+}
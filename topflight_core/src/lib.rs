@@ -1,9 +1,30 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use thiserror::Error;
-use topflight_definitions::{Instruction, ParseError};
-use topflight_vm::{execute, VMError};
+use topflight_definitions::ParseError;
+use topflight_vm::{execute, execute_observed, execute_with_limits};
 
-pub use topflight_vm::{Memory, Routines};
-pub use topflight_definitions::{Routine, Value};
+pub use topflight_definitions::{Call, Instruction, Routine, Value};
+pub use topflight_vm::{
+    Breakpoint, CancellationToken, Debugger, Event, Limits, Location, Memory, Observer, Pause,
+    Profiler, Routines, VMError,
+};
+
+mod check;
+pub use check::{check, Diagnostic, Problem};
+
+mod coverage;
+pub use coverage::Coverage;
+
+mod import;
+use import::Import;
+pub use import::{FileLoader, FileSystem, VirtualFiles};
+
+mod stdlib;
+
+mod trace;
+pub use trace::{TraceFormat, Tracer};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -21,6 +42,344 @@ pub enum Error {
     UnexpectedEndSubroutine(String),
     #[error("Current routine `{0}`, but found end of routine `{1}`")]
     MismatchingEndSubroutine(String, String),
+    // The line where the routine started is kept to be able to point to it
+    #[error("Routine `{0}` is never closed")]
+    UnterminatedRoutine(String, usize),
+    // Every routine of a file is defined before it runs, so the last definition would be used everywhere
+    #[error("Routine `{0}` is already defined at line {1}, a file can only define it once")]
+    RoutineDefinedTwice(String, usize),
+    // Once imported the routines of different files cannot be told apart, one would replace the other
+    #[error("Routine `{0}` is defined {1} and {2}")]
+    RoutineClash(String, String, String),
+    #[error("Invalid import format, expected `IMPORT path.tpf` or `IMPORT path.tpf AS namespace`")]
+    InvalidImportFormat,
+    #[error("It is not possible to import a file inside the routine `{0}`")]
+    ImportInRoutine(String),
+    #[error("Files importing each other: {0}")]
+    ImportCycle(String),
+    #[error("Unable to import `{0}`: {1}")]
+    ImportReadError(String, std::io::Error),
+    #[error("There is no module `{0}` in the standard library")]
+    UnknownStandardModule(String),
+    #[error("In `{path}` at line {line_number}: {error}")]
+    ImportedFileError {
+        path: String,
+        line_number: usize,
+        error: Box<Error>,
+    },
+    #[error("Importing a file is only possible through an interpreter")]
+    ImportNotAvailable,
+}
+
+// Keep everything needed to execute a program fed line by line
+pub struct Interpreter {
+    pub memory: Memory,
+    pub routines: Routines,
+    pub limits: Limits,
+    routine_in_construction: Option<Routine>,
+    routine_start_line: usize,
+    line_number: usize,
+    // Top level instructions kept by the two-phase mode, with their line number
+    program: Vec<(usize, Instruction)>,
+    // Routines defined by the two-phase mode and the line where they start
+    declared_routines: HashMap<String, usize>,
+    // Routines imported by the two-phase mode, with the file and the line where they start
+    imported_routines: HashMap<String, (String, usize)>,
+    // The imported files are relative to the file being executed
+    file_path: Option<PathBuf>,
+    file_loader: Rc<dyn FileLoader>,
+    // Files currently importing the file being executed, to detect cycles
+    import_stack: Vec<PathBuf>,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Interpreter {
+            memory: Memory::default(),
+            routines: Routines::new(),
+            limits: Limits::default(),
+            routine_in_construction: None,
+            routine_start_line: 0,
+            line_number: 0,
+            program: Vec::new(),
+            declared_routines: HashMap::new(),
+            imported_routines: HashMap::new(),
+            file_path: None,
+            file_loader: Rc::new(FileSystem),
+            import_stack: Vec::new(),
+        }
+    }
+}
+
+impl Interpreter {
+    pub fn set_file_path(&mut self, file_path: Option<&Path>) {
+        self.file_path = file_path.map(import::normalize);
+    }
+
+    pub fn set_file_loader(&mut self, file_loader: impl FileLoader + 'static) {
+        self.file_loader = Rc::new(file_loader);
+    }
+
+    pub fn handle_line(&mut self, str: &str, output: &mut String) -> Result<(), Error> {
+        if let Some(instruction) = self.declare_line_impl(str)? {
+            self.execute(&instruction, output)?;
+        }
+        Ok(())
+    }
+
+    // First phase of the two-phase mode: the routines are defined but the top level instructions
+    // are only kept to be executed later, this way a routine can be called before its definition
+    pub fn declare_line(&mut self, str: &str) -> Result<(), Error> {
+        let routine = self
+            .routine_in_construction
+            .as_ref()
+            .map(|routine| (routine.name.clone(), self.routine_start_line));
+        if let Some(instruction) = self.declare_line_impl(str)? {
+            self.program.push((self.line_number, instruction));
+        }
+        // The routine has just been closed
+        if let Some((name, start)) = routine.filter(|_| self.routine_in_construction.is_none()) {
+            if let Some(origin) = self.imported_routines.get(&name) {
+                return Err(Error::RoutineClash(
+                    name,
+                    imported_from(origin),
+                    defined_at(start),
+                ));
+            }
+            if let Some(first_start) = self.declared_routines.insert(name.clone(), start) {
+                return Err(Error::RoutineDefinedTwice(name, first_start));
+            }
+        }
+        Ok(())
+    }
+
+    // Second phase of the two-phase mode: get the top level instructions to execute them
+    pub fn take_program(&mut self) -> Vec<(usize, Instruction)> {
+        std::mem::take(&mut self.program)
+    }
+
+    // First phase of the two-phase mode for a whole file, the top level instructions are given back
+    // with their line. An error comes with the line it is about, the line numbers are the ones of
+    // the file.
+    pub fn load_source(
+        &mut self,
+        source: &str,
+    ) -> Result<Vec<(usize, Instruction)>, (Error, usize)> {
+        // A new file, which can define again the routines of the previous one
+        self.line_number = 0;
+        self.routine_in_construction = None;
+        self.program.clear();
+        self.declared_routines.clear();
+        self.imported_routines.clear();
+
+        for line in source.lines() {
+            if let Err(error) = self.declare_line(line) {
+                // The lines handled after a failed file are not part of its routine
+                self.routine_in_construction = None;
+                return Err((error, self.line_number));
+            }
+        }
+        if let Err(error) = self.finish() {
+            // Point to the start of the routine instead of the end of the file
+            let line_number = match &error {
+                Error::UnterminatedRoutine(_, line_number) => *line_number,
+                _ => self.line_number,
+            };
+            return Err((error, line_number));
+        }
+        Ok(self.take_program())
+    }
+
+    pub fn execute(&mut self, instruction: &Instruction, output: &mut String) -> Result<(), Error> {
+        execute_with_limits(
+            &mut self.memory,
+            &self.routines,
+            instruction,
+            output,
+            &mut self.limits,
+        )?;
+        Ok(())
+    }
+
+    // Slower than `execute`, the observer is notified around every executed instruction
+    pub fn execute_observed(
+        &mut self,
+        instruction: &Instruction,
+        line_number: usize,
+        output: &mut String,
+        observer: &mut dyn Observer,
+    ) -> Result<(), Error> {
+        execute_observed(
+            &mut self.memory,
+            &self.routines,
+            instruction,
+            line_number,
+            output,
+            &mut self.limits,
+            observer,
+        )?;
+        Ok(())
+    }
+
+    fn declare_line_impl(&mut self, str: &str) -> Result<Option<Instruction>, Error> {
+        self.line_number += 1;
+        let was_in_routine = self.routine_in_construction.is_some();
+        let line = declare_line(str, &mut self.routines, &mut self.routine_in_construction)?;
+        if !was_in_routine && self.routine_in_construction.is_some() {
+            self.routine_start_line = self.line_number;
+        }
+        if let Some(routine) = self.routine_in_construction.as_mut() {
+            routine
+                .lines
+                .resize(routine.instructions.len(), self.line_number);
+        }
+
+        match line {
+            Some(Line::Instruction(instruction)) => Ok(Some(instruction)),
+            Some(Line::Import(import)) => {
+                self.import(import)?;
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Only the routines of the imported file are kept, its top level instructions are ignored
+    fn import(&mut self, import: Import) -> Result<(), Error> {
+        let standard_module = import.path.strip_prefix(stdlib::PREFIX);
+        let path = match standard_module {
+            Some(_) => PathBuf::from(&import.path),
+            None => {
+                let directory = self
+                    .file_path
+                    .as_deref()
+                    .and_then(Path::parent)
+                    .unwrap_or(Path::new(""));
+                import::normalize(&directory.join(&import.path))
+            }
+        };
+
+        let mut import_stack = self.import_stack.clone();
+        import_stack.extend(self.file_path.clone());
+        if import_stack.contains(&path) {
+            let cycle = import_stack
+                .iter()
+                .chain([&path])
+                .map(|path| path.display().to_string())
+                .collect::<Vec<String>>()
+                .join(" -> ");
+            return Err(Error::ImportCycle(cycle));
+        }
+
+        let displayed_path = path.display().to_string();
+        let source = match standard_module {
+            Some(name) => match stdlib::get(name) {
+                Some(source) => source.to_string(),
+                None => return Err(Error::UnknownStandardModule(name.to_string())),
+            },
+            None => match self.file_loader.read(&path) {
+                Ok(source) => source,
+                Err(error) => return Err(Error::ImportReadError(displayed_path, error)),
+            },
+        };
+
+        let mut module = Interpreter {
+            file_path: Some(path),
+            file_loader: self.file_loader.clone(),
+            import_stack,
+            ..Interpreter::default()
+        };
+        if let Err((error, line_number)) = module.load_source(&source) {
+            return Err(Error::ImportedFileError {
+                path: displayed_path,
+                line_number,
+                error: Box::new(error),
+            });
+        }
+
+        // The routines imported by the module keep the file defining them
+        let mut origins = module.imported_routines;
+        origins.extend(
+            module
+                .declared_routines
+                .into_iter()
+                .map(|(name, line)| (name, (displayed_path.clone(), line))),
+        );
+        let (routines, origins) = match import.namespace {
+            None => (module.routines, origins),
+            Some(namespace) => (
+                import::add_namespace(module.routines, namespace.as_str()),
+                origins
+                    .into_iter()
+                    .map(|(name, origin)| (import::namespaced(&namespace, &name), origin))
+                    .collect(),
+            ),
+        };
+
+        // The same routine can be imported through several files, but not two different ones
+        let mut names: Vec<&String> = origins.keys().collect();
+        names.sort();
+        for name in names {
+            let origin = &origins[name];
+            if let Some(line) = self.declared_routines.get(name) {
+                return Err(Error::RoutineClash(
+                    name.clone(),
+                    defined_at(*line),
+                    imported_from(origin),
+                ));
+            }
+            match self.imported_routines.get(name) {
+                Some(other) if other != origin => {
+                    let (other, origin) = (imported_from(other), imported_from(origin));
+                    return Err(Error::RoutineClash(name.clone(), other, origin));
+                }
+                _ => (),
+            }
+        }
+        self.imported_routines.extend(origins);
+
+        // The lines of the imported routines are in another file, they would point to the wrong
+        // ones. The trace, the profile and the coverage see them as line 0.
+        self.routines
+            .extend(routines.into_iter().map(|(name, mut routine)| {
+                routine.lines.clear();
+                (name, routine)
+            }));
+        Ok(())
+    }
+
+    // Must be called once there is no more line to handle
+    pub fn finish(&mut self) -> Result<(), Error> {
+        match self.routine_in_construction.take() {
+            None => Ok(()),
+            Some(routine) => Err(Error::UnterminatedRoutine(
+                routine.name,
+                self.routine_start_line,
+            )),
+        }
+    }
+
+    // Routines defined by the file itself, not by its imports, and the line where they start
+    pub fn declared_routines(&self) -> &HashMap<String, usize> {
+        &self.declared_routines
+    }
+
+    pub fn routine_in_construction(&self) -> Option<&Routine> {
+        self.routine_in_construction.as_ref()
+    }
+
+    // Number of lines handled so far
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+}
+
+fn defined_at(line: usize) -> String {
+    format!("at line {}", line)
+}
+
+fn imported_from((path, line): &(String, usize)) -> String {
+    format!("in `{}` at line {}", path, line)
 }
 
 pub fn handle_line(
@@ -30,8 +389,22 @@ pub fn handle_line(
     routine_in_construction: &mut Option<Routine>,
     output: &mut String,
 ) -> Result<(), Error> {
+    match declare_line(str, routines, routine_in_construction)? {
+        Some(Line::Instruction(instruction)) => execute(memory, routines, &instruction, output)?,
+        Some(Line::Import(_)) => return Err(Error::ImportNotAvailable),
+        _ => (),
+    }
+    Ok(())
+}
+
+// Build the routines, the top level lines (instructions and imports) are given back to be handled
+fn declare_line(
+    str: &str,
+    routines: &mut Routines,
+    routine_in_construction: &mut Option<Routine>,
+) -> Result<Option<Line>, Error> {
     if str.is_empty() || str.starts_with('#') {
-        return Ok(());
+        return Ok(None);
     }
 
     match parse_line(str)? {
@@ -39,13 +412,17 @@ pub fn handle_line(
         Line::RoutineEnd(routine_name) => {
             end_routine(routine_name, routine_in_construction, routines)?
         }
+        Line::Import(import) => match routine_in_construction.as_ref() {
+            None => return Ok(Some(Line::Import(import))),
+            Some(routine) => return Err(Error::ImportInRoutine(routine.name.clone())),
+        },
         Line::Instruction(instruction) => match routine_in_construction.as_mut() {
-            None => execute(memory, routines, &instruction, output)?,
+            None => return Ok(Some(Line::Instruction(instruction))),
             Some(routine) => routine.instructions.push(instruction),
         },
     };
 
-    Ok(())
+    Ok(None)
 }
 
 fn start_routine(
@@ -84,6 +461,7 @@ fn end_routine(
 enum Line {
     RoutineStart(String),
     RoutineEnd(String),
+    Import(Import),
     Instruction(Instruction),
 }
 
@@ -106,6 +484,11 @@ fn parse_line(str: &str) -> Result<Line, Error> {
                 str,
             ))))
         }
+    } else if str == "IMPORT" || str.starts_with("IMPORT ") {
+        match import::parse_import(str) {
+            Some(import) => Ok(Line::Import(import)),
+            None => Err(Error::InvalidImportFormat),
+        }
     } else {
         Ok(Line::Instruction(Instruction::parse(str)?))
     }
@@ -130,10 +513,229 @@ fn get_routine_name_at_end(str: &str) -> &str {
 
 #[cfg(test)]
 mod tests {
-    //use super::*;
+    use super::*;
 
     #[test]
     fn test_todo() {
         assert_eq!(true, true);
     }
+
+    #[test]
+    fn test_finish_unterminated_routine() {
+        let mut interpreter = Interpreter::default();
+        let mut output = String::new();
+        for line in ["STORE a INTEGER(1)", "", "<never_closed>", "PRINT a"] {
+            interpreter
+                .handle_line(line, &mut output)
+                .expect("The lines are valid");
+        }
+        match interpreter.finish() {
+            Err(Error::UnterminatedRoutine(name, line)) => {
+                assert_eq!(name, "never_closed");
+                assert_eq!(line, 3);
+            }
+            _ => panic!("The routine is not closed, finish should have failed"),
+        }
+        assert!(!interpreter.routines.contains_key("never_closed"));
+    }
+
+    #[test]
+    fn test_finish_closed_routine() {
+        let mut interpreter = Interpreter::default();
+        let mut output = String::new();
+        for line in ["<closed>", "</closed>"] {
+            interpreter
+                .handle_line(line, &mut output)
+                .expect("The lines are valid");
+        }
+        assert!(interpreter.finish().is_ok());
+    }
+
+    #[test]
+    fn test_two_phase_forward_call() {
+        let mut interpreter = Interpreter::default();
+        let mut output = String::new();
+        let lines = [
+            "CALL main",
+            "<main>",
+            "STORE a STRING(\"hello\")",
+            "PRINT a",
+            "</main>",
+        ];
+        for line in lines {
+            interpreter.declare_line(line).expect("The lines are valid");
+        }
+        interpreter.finish().expect("Every routine is closed");
+        assert_eq!(interpreter.routines.get("main").unwrap().lines, vec![3, 4]);
+
+        let program = interpreter.take_program();
+        assert_eq!(program.len(), 1);
+        assert_eq!(program[0].0, 1);
+        for (_, instruction) in program.iter() {
+            interpreter
+                .execute(instruction, &mut output)
+                .expect("The routine is defined");
+        }
+        assert_eq!(output, "hello");
+    }
+
+    #[test]
+    fn test_routine_defined_twice() {
+        let lines = [
+            "<r>",
+            "PRINT first",
+            "</r>",
+            "CALL r",
+            "<r>",
+            "PRINT second",
+            "</r>",
+            "CALL r",
+        ];
+        let mut interpreter = Interpreter::default();
+        let error = lines
+            .iter()
+            .find_map(|line| interpreter.declare_line(line).err())
+            .expect("The second definition is refused");
+        assert!(matches!(error, Error::RoutineDefinedTwice(name, 1) if name == "r"));
+        assert_eq!(interpreter.line_number(), 7);
+
+        // Line by line each definition is used until the next one
+        let mut interpreter = Interpreter::default();
+        let mut output = String::new();
+        interpreter.memory.store("first", Value::from("first"));
+        interpreter.memory.store("second", Value::from("second"));
+        for line in lines {
+            interpreter
+                .handle_line(line, &mut output)
+                .expect("A routine can be redefined");
+        }
+        assert_eq!(output, "firstsecond");
+    }
+
+    #[test]
+    fn test_load_source() {
+        let mut interpreter = Interpreter::default();
+        let mut output = String::new();
+        let source = "CALL r\n\n<r>\nSTORE s STRING(\"r\")\nPRINT s\n</r>\n";
+        // Loading a file again redefines its routines
+        for _ in 0..2 {
+            let program = interpreter.load_source(source).expect("The file is valid");
+            assert_eq!(program.len(), 1);
+            assert_eq!(program[0].0, 1);
+            for (_, instruction) in program {
+                interpreter.execute(&instruction, &mut output).unwrap();
+            }
+        }
+        assert_eq!(output, "rr");
+        assert_eq!(interpreter.routines.get("r").unwrap().lines, vec![4, 5]);
+
+        let (error, line_number) = interpreter
+            .load_source("PRINT s\n<open>\nPRINT s\n")
+            .expect_err("The routine is not closed");
+        assert!(matches!(error, Error::UnterminatedRoutine(_, 2)));
+        assert_eq!(line_number, 2);
+
+        let (error, line_number) = interpreter
+            .load_source("<r>\nNOPE x\n</r>\n")
+            .expect_err("The instruction does not exist");
+        assert!(matches!(error, Error::ParsingError(_)));
+        assert_eq!(line_number, 2);
+        assert!(interpreter.routine_in_construction().is_none());
+
+        let (error, line_number) = interpreter
+            .load_source("<r>\n</r>\n\n<r>\n</r>\n")
+            .expect_err("The routine is defined twice");
+        assert!(matches!(error, Error::RoutineDefinedTwice(_, 1)));
+        assert_eq!(line_number, 5);
+    }
+
+    fn run_with_files(code: &str, files: &[(&str, &str)]) -> Result<String, Error> {
+        let mut virtual_files = VirtualFiles::default();
+        for (path, source) in files {
+            virtual_files.add(path, source.to_string());
+        }
+        let mut interpreter = Interpreter::default();
+        interpreter.set_file_loader(virtual_files);
+        interpreter.set_file_path(Some(Path::new("main.tpf")));
+
+        let mut output = String::new();
+        let program = interpreter.load_source(code).map_err(|(error, _)| error)?;
+        for (_, instruction) in program {
+            interpreter.execute(&instruction, &mut output)?;
+        }
+        Ok(output)
+    }
+
+    const HELLO: &str = "<hello>\nCALL say\n</hello>\n<say>\nSTORE s STRING(\"hello\")\nPRINT s\n</say>\nCALL hello\n";
+
+    #[test]
+    fn test_import() {
+        let output = run_with_files(
+            "IMPORT lib/hello.tpf\nCALL hello",
+            &[("lib/hello.tpf", HELLO)],
+        )
+        .expect("The import is valid");
+        // The top level instructions of the imported file are not executed
+        assert_eq!(output, "hello");
+    }
+
+    #[test]
+    fn test_import_with_namespace() {
+        let code = "IMPORT hello.tpf AS greetings\nCALL greetings::hello";
+        let output = run_with_files(code, &[("hello.tpf", HELLO)]).expect("The import is valid");
+        assert_eq!(output, "hello");
+
+        let code = "IMPORT hello.tpf AS greetings\nCALL hello";
+        assert!(matches!(
+            run_with_files(code, &[("hello.tpf", HELLO)]),
+            Err(Error::VMError(VMError::RoutineDoesNotExist(_)))
+        ));
+    }
+
+    #[test]
+    fn test_import_relative_to_the_importing_file() {
+        let files = [
+            ("lib/a.tpf", "IMPORT ../other/b.tpf\n"),
+            ("other/b.tpf", HELLO),
+        ];
+        let output =
+            run_with_files("IMPORT lib/a.tpf\nCALL hello", &files).expect("The import is valid");
+        assert_eq!(output, "hello");
+    }
+
+    #[test]
+    fn test_import_clash() {
+        let files = [
+            ("hello.tpf", HELLO),
+            ("other.tpf", "\n<say>\n</say>\n"),
+            ("both.tpf", "IMPORT hello.tpf\n"),
+        ];
+        let clash = |code| run_with_files(code, &files).unwrap_err().to_string();
+        assert_eq!(
+            clash("<say>\n</say>\nIMPORT hello.tpf"),
+            "Routine `say` is defined at line 1 and in `hello.tpf` at line 4"
+        );
+        assert_eq!(
+            clash("IMPORT hello.tpf\n<say>\n</say>"),
+            "Routine `say` is defined in `hello.tpf` at line 4 and at line 2"
+        );
+        assert_eq!(
+            clash("IMPORT hello.tpf\nIMPORT other.tpf"),
+            "Routine `say` is defined in `hello.tpf` at line 4 and in `other.tpf` at line 2"
+        );
+
+        // The same routine imported through another file, or under another name, is not a clash
+        let code = "IMPORT both.tpf\nIMPORT hello.tpf\nIMPORT other.tpf AS other\nCALL hello";
+        assert_eq!(run_with_files(code, &files).unwrap(), "hello");
+    }
+
+    #[test]
+    fn test_import_cycle() {
+        let files = [("a.tpf", "IMPORT b.tpf"), ("b.tpf", "IMPORT ./a.tpf")];
+        let error = run_with_files("IMPORT a.tpf", &files).expect_err("There is a cycle");
+        assert_eq!(
+            error.to_string(),
+            "In `a.tpf` at line 1: In `b.tpf` at line 1: Files importing each other: main.tpf -> a.tpf -> b.tpf -> a.tpf"
+        );
+    }
 }
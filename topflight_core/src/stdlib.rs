@@ -0,0 +1,223 @@
+// Files bundled with the interpreter, they are imported with `IMPORT std/name`
+pub(crate) const PREFIX: &str = "std/";
+
+const MODULES: [(&str, &str); 4] = [
+    ("canvas", include_str!("../stdlib/canvas.tpf")),
+    ("range", include_str!("../stdlib/range.tpf")),
+    ("sort", include_str!("../stdlib/sort.tpf")),
+    ("strings", include_str!("../stdlib/strings.tpf")),
+];
+
+pub(crate) fn get(name: &str) -> Option<&'static str> {
+    MODULES
+        .iter()
+        .find(|(module_name, _)| *module_name == name)
+        .map(|(_, source)| *source)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Interpreter, Value};
+
+    // Import a module, store the given variables then call the routine
+    fn call(module: &str, routine: &str, variables: Vec<(&str, Value)>) -> (Interpreter, String) {
+        let mut interpreter = Interpreter::default();
+        let mut output = String::new();
+        interpreter
+            .handle_line(
+                format!("IMPORT std/{} AS {}", module, module).as_str(),
+                &mut output,
+            )
+            .expect("The module exists and is valid");
+        for (name, value) in variables {
+            interpreter.memory.store(name, value);
+        }
+        interpreter
+            .handle_line(
+                format!("CALL {}::{}", module, routine).as_str(),
+                &mut output,
+            )
+            .expect("The routine works");
+        (interpreter, output)
+    }
+
+    fn load(interpreter: &Interpreter, name: &str) -> Value {
+        interpreter
+            .memory
+            .load(name)
+            .expect("The result is stored")
+            .clone()
+    }
+
+    #[test]
+    fn test_every_module_is_valid() {
+        for (name, _) in super::MODULES {
+            let mut interpreter = Interpreter::default();
+            let mut output = String::new();
+            interpreter
+                .handle_line(format!("IMPORT std/{}", name).as_str(), &mut output)
+                .expect("The module exists and is valid");
+            assert!(!interpreter.routines.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_unknown_module() {
+        let mut interpreter = Interpreter::default();
+        let mut output = String::new();
+        assert!(interpreter
+            .handle_line("IMPORT std/does_not_exist", &mut output)
+            .is_err());
+    }
+
+    #[test]
+    fn test_print_range() {
+        let variables = vec![
+            ("print_range_start", Value::Integer(-1)),
+            ("print_range_end", Value::Integer(2)),
+        ];
+        let (_, output) = call("range", "print_range", variables);
+        assert_eq!(output, "-1\n0\n1\n2\n");
+
+        let variables = vec![
+            ("print_range_start", Value::Integer(3)),
+            ("print_range_end", Value::Integer(2)),
+        ];
+        let (_, output) = call("range", "print_range", variables);
+        assert_eq!(output, "");
+    }
+
+    #[test]
+    fn test_sum_range() {
+        let variables = vec![
+            ("sum_range_start", Value::Integer(1)),
+            ("sum_range_end", Value::Integer(1000)),
+        ];
+        let (interpreter, _) = call("range", "sum_range", variables);
+        assert_eq!(
+            load(&interpreter, "sum_range_result"),
+            Value::Integer(500500)
+        );
+    }
+
+    #[test]
+    fn test_sort_array() {
+        let variables = vec![("sort_array_input", Value::from(vec![3, 1, 2, -5, 2]))];
+        let (interpreter, _) = call("sort", "sort_array", variables);
+        assert_eq!(
+            load(&interpreter, "sort_array_result"),
+            Value::from(vec![-5, 1, 2, 2, 3])
+        );
+        assert_eq!(
+            load(&interpreter, "sort_array_input"),
+            Value::from(vec![3, 1, 2, -5, 2])
+        );
+
+        let words = vec![String::from("b"), String::from("c"), String::from("a")];
+        let variables = vec![("sort_array_input", Value::from(words))];
+        let (interpreter, _) = call("sort", "sort_array", variables);
+        let sorted = vec![String::from("a"), String::from("b"), String::from("c")];
+        assert_eq!(load(&interpreter, "sort_array_result"), Value::from(sorted));
+
+        for input in [Vec::<i64>::new(), vec![1]] {
+            let variables = vec![("sort_array_input", Value::from(input.clone()))];
+            let (interpreter, _) = call("sort", "sort_array", variables);
+            assert_eq!(load(&interpreter, "sort_array_result"), Value::from(input));
+        }
+    }
+
+    #[test]
+    fn test_print_line() {
+        let variables = vec![("print_line_input", Value::from("hi"))];
+        let (_, output) = call("strings", "print_line", variables);
+        assert_eq!(output, "hi\n");
+    }
+
+    #[test]
+    fn test_repeat_string() {
+        let variables = vec![
+            ("repeat_string_input", Value::from("ab")),
+            ("repeat_string_count", Value::Integer(3)),
+        ];
+        let (interpreter, _) = call("strings", "repeat_string", variables);
+        assert_eq!(
+            load(&interpreter, "repeat_string_result"),
+            Value::from("ababab")
+        );
+    }
+
+    #[test]
+    fn test_surround_string() {
+        let variables = vec![
+            ("surround_string_input", Value::from("cool")),
+            ("surround_string_left", Value::from("<")),
+            ("surround_string_right", Value::from(">")),
+        ];
+        let (interpreter, _) = call("strings", "surround_string", variables);
+        assert_eq!(
+            load(&interpreter, "surround_string_result"),
+            Value::from("<cool>")
+        );
+    }
+
+    #[test]
+    fn test_canvas_resize() {
+        let variables = vec![
+            ("canvas_width", Value::Integer(150)),
+            ("canvas_height", Value::Integer(100)),
+        ];
+        let (_, output) = call("canvas", "canvas_resize", variables);
+        assert_eq!(output, "__CANVAS_RESIZE__ 150 100\n");
+    }
+
+    #[test]
+    fn test_canvas_pixel() {
+        let variables = vec![
+            ("canvas_x", Value::Integer(1)),
+            ("canvas_y", Value::Integer(2)),
+            ("canvas_color", Value::from("red")),
+        ];
+        let (_, output) = call("canvas", "canvas_pixel", variables);
+        assert_eq!(output, "__PIXEL_PUT__ 1 2 red\n");
+    }
+
+    #[test]
+    fn test_canvas_lines() {
+        let variables = vec![
+            ("canvas_x", Value::Integer(1)),
+            ("canvas_y", Value::Integer(2)),
+            ("canvas_length", Value::Integer(2)),
+            ("canvas_color", Value::from("red")),
+        ];
+        let (interpreter, output) = call("canvas", "canvas_horizontal_line", variables);
+        assert_eq!(output, "__PIXEL_PUT__ 1 2 red\n__PIXEL_PUT__ 2 2 red\n");
+        assert_eq!(load(&interpreter, "canvas_x"), Value::Integer(1));
+
+        let variables = vec![
+            ("canvas_x", Value::Integer(1)),
+            ("canvas_y", Value::Integer(2)),
+            ("canvas_length", Value::Integer(2)),
+            ("canvas_color", Value::from("red")),
+        ];
+        let (interpreter, output) = call("canvas", "canvas_vertical_line", variables);
+        assert_eq!(output, "__PIXEL_PUT__ 1 2 red\n__PIXEL_PUT__ 1 3 red\n");
+        assert_eq!(load(&interpreter, "canvas_y"), Value::Integer(2));
+    }
+
+    #[test]
+    fn test_canvas_rectangle() {
+        let variables = vec![
+            ("canvas_x", Value::Integer(1)),
+            ("canvas_y", Value::Integer(2)),
+            ("canvas_width", Value::Integer(2)),
+            ("canvas_height", Value::Integer(2)),
+            ("canvas_color", Value::from("blue")),
+        ];
+        let (interpreter, output) = call("canvas", "canvas_rectangle", variables);
+        assert_eq!(
+            output,
+            "__PIXEL_PUT__ 1 2 blue\n__PIXEL_PUT__ 2 2 blue\n__PIXEL_PUT__ 1 3 blue\n__PIXEL_PUT__ 2 3 blue\n"
+        );
+        assert_eq!(load(&interpreter, "canvas_y"), Value::Integer(2));
+    }
+}
@@ -0,0 +1,213 @@
+use std::io::Write;
+use topflight_vm::{Event, Memory, Observer, VMError};
+
+use crate::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    // One readable line per instruction
+    Text,
+    // One JSON object per line
+    Json,
+}
+
+// Writes every executed instruction with its line, its routine and the values it read and wrote.
+// The instruction which stopped the program is written with its error.
+pub struct Tracer<W: Write> {
+    format: TraceFormat,
+    output: W,
+    // Values read by the current instruction, taken before it modifies them
+    read: Vec<(String, Option<Value>)>,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(format: TraceFormat, output: W) -> Tracer<W> {
+        Tracer {
+            format,
+            output,
+            read: Vec::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+
+    fn text(
+        &self,
+        event: &Event,
+        written: &[(&str, Option<Value>)],
+        error: Option<&VMError>,
+    ) -> String {
+        let mut line = format!("line {}", event.line);
+        if let Some(routine) = event.routine() {
+            line += &format!(" in {}", routine);
+        }
+        line += &format!(": {}", event.instruction.name());
+
+        let values = |values: &mut dyn Iterator<Item = (&str, &Option<Value>)>| {
+            values
+                .map(|(name, value)| match value {
+                    Some(value) => format!("{} = {}", name, value.literal()),
+                    None => format!("{} undefined", name),
+                })
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+        if !self.read.is_empty() {
+            let mut read = self.read.iter().map(|(name, value)| (name.as_str(), value));
+            line += &format!(" read {}", values(&mut read));
+        }
+        if !written.is_empty() {
+            let mut written = written.iter().map(|(name, value)| (*name, value));
+            line += &format!(" wrote {}", values(&mut written));
+        }
+        if let Some(error) = error {
+            line += &format!(" failed: {}", error);
+        }
+        line
+    }
+
+    fn json(
+        &self,
+        event: &Event,
+        written: &[(&str, Option<Value>)],
+        error: Option<&VMError>,
+    ) -> String {
+        let read: serde_json::Map<String, serde_json::Value> = self
+            .read
+            .iter()
+            .map(|(name, value)| (name.clone(), json_value(value.as_ref())))
+            .collect();
+        let written: serde_json::Map<String, serde_json::Value> = written
+            .iter()
+            .map(|(name, value)| (name.to_string(), json_value(value.as_ref())))
+            .collect();
+        let mut json = serde_json::json!({
+            "line": event.line,
+            "routine": event.routine(),
+            "instruction": event.instruction.name(),
+            "read": read,
+            "written": written,
+        });
+        if let Some(error) = error {
+            json["error"] = serde_json::json!(error.to_string());
+        }
+        json.to_string()
+    }
+
+    fn write(&mut self, event: &Event, written: &[(&str, Option<Value>)], error: Option<&VMError>) {
+        let line = match self.format {
+            TraceFormat::Text => self.text(event, written, error),
+            TraceFormat::Json => self.json(event, written, error),
+        };
+        // The trace is only a help, failing to write it should not stop the program
+        let _ = writeln!(self.output, "{}", line);
+    }
+}
+
+impl<W: Write> Observer for Tracer<W> {
+    fn before(&mut self, event: &Event, memory: &Memory) {
+        self.read.clear();
+        for name in event.instruction.read_variables() {
+            self.read
+                .push((name.to_string(), memory.load(name).ok().cloned()));
+        }
+    }
+
+    fn after(&mut self, event: &Event, memory: &Memory) {
+        let written: Vec<(&str, Option<Value>)> = event
+            .instruction
+            .written_variables()
+            .into_iter()
+            .map(|name| (name, memory.load(name).ok().cloned()))
+            .collect();
+        self.write(event, &written, None);
+    }
+
+    // The values read tell why it failed
+    fn failed(&mut self, event: &Event, _memory: &Memory, error: &VMError) {
+        self.write(event, &[], Some(error));
+    }
+}
+
+fn json_value(value: Option<&Value>) -> serde_json::Value {
+    match value {
+        None => serde_json::Value::Null,
+        Some(Value::Integer(i)) => serde_json::json!(i),
+        // NaN and infinites become null
+        Some(Value::Number(n)) => serde_json::json!(n),
+        Some(Value::Char(c)) => serde_json::json!(c),
+        Some(Value::String(s)) => serde_json::json!(s.as_str()),
+        Some(Value::Boolean(b)) => serde_json::json!(b),
+        Some(Value::ArrayOfInteger(v)) => serde_json::json!(v.as_slice()),
+        Some(Value::ArrayOfNumber(v)) => serde_json::json!(v.as_slice()),
+        Some(Value::ArrayOfString(v)) => serde_json::json!(v.as_slice()),
+        Some(Value::ArrayOfBoolean(v)) => serde_json::json!(v.as_slice()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Interpreter;
+
+    fn trace(format: TraceFormat, last_line: &str) -> String {
+        let mut interpreter = Interpreter::default();
+        let source = format!(
+            "<increment>\nADD i one i\n</increment>\nSTORE i INTEGER(0)\nSTORE one INTEGER(1)\nCALL increment\n{}",
+            last_line
+        );
+        let program = interpreter
+            .load_source(&source)
+            .expect("The lines are valid");
+
+        let mut tracer = Tracer::new(format, Vec::new());
+        let mut output = String::new();
+        for (line, instruction) in program {
+            let result = interpreter.execute_observed(&instruction, line, &mut output, &mut tracer);
+            if result.is_err() {
+                break;
+            }
+        }
+        String::from_utf8(tracer.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn test_text_trace() {
+        assert_eq!(
+            trace(TraceFormat::Text, "PRINT i"),
+            "line 4: STORE wrote i = INTEGER(0)
+line 5: STORE wrote one = INTEGER(1)
+line 6: CALL
+line 2 in increment: ADD read i = INTEGER(0), one = INTEGER(1) wrote i = INTEGER(1)
+line 7: PRINT read i = INTEGER(1)
+"
+        );
+    }
+
+    #[test]
+    fn test_json_trace() {
+        let trace = trace(TraceFormat::Json, "");
+        let last = trace.lines().last().unwrap();
+        assert_eq!(
+            last,
+            r#"{"instruction":"ADD","line":2,"read":{"i":0,"one":1},"routine":"increment","written":{"i":1}}"#
+        );
+    }
+
+    #[test]
+    fn test_failed_instruction_trace() {
+        let text = trace(TraceFormat::Text, "DIVIDE one zero i");
+        assert_eq!(
+            text.lines().last().unwrap(),
+            "line 7: DIVIDE read one = INTEGER(1), zero undefined failed: Variable `zero` does not exist"
+        );
+        let json = trace(TraceFormat::Json, "DIVIDE one zero i");
+        assert!(json
+            .lines()
+            .last()
+            .unwrap()
+            .contains(r#""error":"Variable `zero` does not exist""#));
+    }
+}
@@ -0,0 +1,147 @@
+// Runs every program of the directories below and compares what it prints with the expected
+// outputs next to it:
+// * `program.stdout.expected` is the output of the program
+// * `program.stderr.expected` is the error which stopped it, if any
+// * `program.args` contains the arguments given to the program, one per line
+// A missing expected file means the output must be empty. Run with `BLESS=1` to write the
+// expected files from the current outputs instead of comparing them.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use topflight_core::{Interpreter, Value};
+
+// Relative to this crate
+const DIRECTORIES: &[&str] = &["../TopFlightExamples", "tests/programs"];
+
+// Far above what any program needs, a program stuck in a loop fails instead of hanging the tests
+const MAX_STEPS: u64 = 10_000_000;
+
+struct Outputs {
+    stdout: String,
+    stderr: String,
+}
+
+// Same as the command line interpreter: every routine is defined before the program is executed
+fn run(path: &Path) -> Outputs {
+    let source = fs::read_to_string(path).expect("Readable program");
+    let lines: Vec<&str> = source.lines().collect();
+    let mut outputs = Outputs {
+        stdout: String::new(),
+        stderr: String::new(),
+    };
+    let mut error_at = |error: topflight_core::Error, line_number: usize| {
+        outputs.stderr = format!(
+            "Error at line {}: {}\n\t{}\n",
+            line_number,
+            error,
+            lines[line_number - 1]
+        );
+    };
+
+    let mut interpreter = Interpreter::default();
+    interpreter.limits.max_steps = Some(MAX_STEPS);
+    interpreter.set_file_path(Some(path));
+    let args = args(path);
+    if !args.is_empty() {
+        interpreter.memory.store("args", Value::from(args));
+    }
+
+    let program = match interpreter.load_source(&source) {
+        Ok(program) => program,
+        Err((error, line_number)) => {
+            error_at(error, line_number);
+            return outputs;
+        }
+    };
+    let mut stdout = String::new();
+    for (line_number, instruction) in program {
+        if let Err(error) = interpreter.execute(&instruction, &mut stdout) {
+            error_at(error, line_number);
+            break;
+        }
+    }
+    outputs.stdout = stdout;
+    outputs
+}
+
+fn args(path: &Path) -> Vec<String> {
+    match fs::read_to_string(path.with_extension("args")) {
+        Ok(args) => args.lines().map(String::from).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn programs() -> Vec<PathBuf> {
+    let crate_directory = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut programs = Vec::new();
+    for directory in DIRECTORIES {
+        let entries = fs::read_dir(crate_directory.join(directory)).expect("Readable directory");
+        for entry in entries {
+            let path = entry.expect("Readable directory entry").path();
+            if path.extension().is_some_and(|extension| extension == "tpf") {
+                programs.push(path);
+            }
+        }
+    }
+    programs.sort();
+    programs
+}
+
+// Returns a description of the difference, if any
+fn compare(path: &Path, actual: &str, bless: bool) -> Option<String> {
+    let expected = fs::read_to_string(path).unwrap_or_default();
+    if bless {
+        if actual.is_empty() {
+            let _ = fs::remove_file(path);
+        } else {
+            fs::write(path, actual).expect("Writable expected file");
+        }
+        return None;
+    }
+    if expected == actual {
+        return None;
+    }
+
+    let mut expected_lines = expected.lines();
+    let mut actual_lines = actual.lines();
+    for line_number in 1.. {
+        match (expected_lines.next(), actual_lines.next()) {
+            (Some(expected), Some(actual)) if expected == actual => continue,
+            (None, None) => {
+                return Some(format!("{}: different line endings", path.display()));
+            }
+            (expected, actual) => {
+                return Some(format!(
+                    "{}: first difference at line {}\n\texpected: {}\n\tactual:   {}",
+                    path.display(),
+                    line_number,
+                    expected.unwrap_or("(end of the output)"),
+                    actual.unwrap_or("(end of the output)")
+                ));
+            }
+        }
+    }
+    unreachable!("The outputs are different so a line is different")
+}
+
+#[test]
+fn test_examples() {
+    let bless = std::env::var_os("BLESS").is_some();
+    let mut failures = Vec::new();
+    for program in programs() {
+        let outputs = run(&program);
+        let file_name = program
+            .file_stem()
+            .expect("A program file")
+            .to_string_lossy();
+        let stdout = program.with_file_name(format!("{}.stdout.expected", file_name));
+        let stderr = program.with_file_name(format!("{}.stderr.expected", file_name));
+        failures.extend(compare(&stdout, &outputs.stdout, bless));
+        failures.extend(compare(&stderr, &outputs.stderr, bless));
+    }
+    assert!(
+        failures.is_empty(),
+        "Unexpected outputs, run with BLESS=1 to accept them:\n{}",
+        failures.join("\n")
+    );
+}
@@ -8,6 +8,8 @@ use topflight_macro_utils::GenericInstruction;
 pub struct Routine {
     pub name: String,
     pub instructions: Vec<Instruction>,
+    // Source line of each instruction, empty when unknown
+    pub lines: Vec<usize>,
 }
 
 impl Routine {
@@ -15,6 +17,7 @@ impl Routine {
         Routine {
             name,
             instructions: Vec::new(),
+            lines: Vec::new(),
         }
     }
 }
@@ -50,6 +53,19 @@ pub enum Instruction {
     PushBack(PushBack),
     Concat(Concat),
     Erase(Erase),
+    Sort(Sort),
+    Reverse(Reverse),
+    Fill(Fill),
+    IndexOf(IndexOf),
+    Contains(Contains),
+    Slice(Slice),
+    Range(Range),
+    Assert(Assert),
+    AssertEqual(AssertEqual),
+    AssertError(AssertError),
+    TypeOf(TypeOf),
+    IsDefined(IsDefined),
+    IsArray(IsArray),
 }
 
 impl Instruction {
@@ -86,9 +102,236 @@ impl Instruction {
             PushBack::NAME => Ok(PushBack::parse(chars.as_str())?),
             Concat::NAME => Ok(Concat::parse(chars.as_str())?),
             Erase::NAME => Ok(Erase::parse(chars.as_str())?),
+            Sort::NAME => Ok(Sort::parse(chars.as_str())?),
+            Reverse::NAME => Ok(Reverse::parse(chars.as_str())?),
+            Fill::NAME => Ok(Fill::parse(chars.as_str())?),
+            IndexOf::NAME => Ok(IndexOf::parse(chars.as_str())?),
+            Contains::NAME => Ok(Contains::parse(chars.as_str())?),
+            Slice::NAME => Ok(Slice::parse(chars.as_str())?),
+            Range::NAME => Ok(Range::parse(chars.as_str())?),
+            Assert::NAME => Ok(Assert::parse(chars.as_str())?),
+            AssertEqual::NAME => Ok(AssertEqual::parse(chars.as_str())?),
+            AssertError::NAME => Ok(AssertError::parse(chars.as_str())?),
+            TypeOf::NAME => Ok(TypeOf::parse(chars.as_str())?),
+            IsDefined::NAME => Ok(IsDefined::parse(chars.as_str())?),
+            IsArray::NAME => Ok(IsArray::parse(chars.as_str())?),
             _ => Err(ParseError::InstructionDoesNotExist(name)),
         }
     }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Instruction::Store(_) => Store::NAME,
+            Instruction::Copy(_) => Copy::NAME,
+            Instruction::Free(_) => Free::NAME,
+            Instruction::Print(_) => Print::NAME,
+            Instruction::Call(_) => Call::NAME,
+            Instruction::CallIf(_) => CallIf::NAME,
+            Instruction::Add(_) => Add::NAME,
+            Instruction::Substract(_) => Substract::NAME,
+            Instruction::Multiply(_) => Multiply::NAME,
+            Instruction::Divide(_) => Divide::NAME,
+            Instruction::Modulo(_) => Modulo::NAME,
+            Instruction::LogicalAnd(_) => LogicalAnd::NAME,
+            Instruction::LogicalOr(_) => LogicalOr::NAME,
+            Instruction::LogicalNot(_) => LogicalNot::NAME,
+            Instruction::CompareEqual(_) => CompareEqual::NAME,
+            Instruction::CompareDifferent(_) => CompareDifferent::NAME,
+            Instruction::CompareLess(_) => CompareLess::NAME,
+            Instruction::CompareLessOrEqual(_) => CompareLessOrEqual::NAME,
+            Instruction::CompareGreater(_) => CompareGreater::NAME,
+            Instruction::CompareGreaterOrEqual(_) => CompareGreaterOrEqual::NAME,
+            Instruction::GetAt(_) => GetAt::NAME,
+            Instruction::StoreAt(_) => StoreAt::NAME,
+            Instruction::CopyAt(_) => CopyAt::NAME,
+            Instruction::Size(_) => Size::NAME,
+            Instruction::Resize(_) => Resize::NAME,
+            Instruction::Insert(_) => Insert::NAME,
+            Instruction::PushBack(_) => PushBack::NAME,
+            Instruction::Concat(_) => Concat::NAME,
+            Instruction::Erase(_) => Erase::NAME,
+            Instruction::Sort(_) => Sort::NAME,
+            Instruction::Reverse(_) => Reverse::NAME,
+            Instruction::Fill(_) => Fill::NAME,
+            Instruction::IndexOf(_) => IndexOf::NAME,
+            Instruction::Contains(_) => Contains::NAME,
+            Instruction::Slice(_) => Slice::NAME,
+            Instruction::Range(_) => Range::NAME,
+            Instruction::Assert(_) => Assert::NAME,
+            Instruction::AssertEqual(_) => AssertEqual::NAME,
+            Instruction::AssertError(_) => AssertError::NAME,
+            Instruction::TypeOf(_) => TypeOf::NAME,
+            Instruction::IsDefined(_) => IsDefined::NAME,
+            Instruction::IsArray(_) => IsArray::NAME,
+        }
+    }
+
+    // Variables that must exist before the instruction is executed
+    pub fn read_variables(&self) -> Vec<&str> {
+        match self {
+            Instruction::Store(_) | Instruction::Call(_) => vec![],
+            Instruction::Copy(Copy { input, .. }) => vec![input],
+            Instruction::Free(Free { dest }) => vec![dest],
+            Instruction::Print(Print { input }) => vec![input],
+            Instruction::CallIf(CallIf {
+                boolean_input_name, ..
+            }) => vec![boolean_input_name],
+            Instruction::Add(Add {
+                input_a, input_b, ..
+            })
+            | Instruction::Substract(Substract {
+                input_a, input_b, ..
+            })
+            | Instruction::Multiply(Multiply {
+                input_a, input_b, ..
+            })
+            | Instruction::Divide(Divide {
+                input_a, input_b, ..
+            })
+            | Instruction::Modulo(Modulo {
+                input_a, input_b, ..
+            })
+            | Instruction::LogicalAnd(LogicalAnd {
+                input_a, input_b, ..
+            })
+            | Instruction::LogicalOr(LogicalOr {
+                input_a, input_b, ..
+            })
+            | Instruction::CompareEqual(CompareEqual {
+                input_a, input_b, ..
+            })
+            | Instruction::CompareDifferent(CompareDifferent {
+                input_a, input_b, ..
+            })
+            | Instruction::CompareLess(CompareLess {
+                input_a, input_b, ..
+            })
+            | Instruction::CompareLessOrEqual(CompareLessOrEqual {
+                input_a, input_b, ..
+            })
+            | Instruction::CompareGreater(CompareGreater {
+                input_a, input_b, ..
+            })
+            | Instruction::CompareGreaterOrEqual(CompareGreaterOrEqual {
+                input_a, input_b, ..
+            })
+            | Instruction::Concat(Concat {
+                input_a, input_b, ..
+            }) => vec![input_a, input_b],
+            Instruction::LogicalNot(LogicalNot { input, .. }) => vec![input],
+            Instruction::GetAt(GetAt {
+                array_input, index, ..
+            }) => vec![array_input, index],
+            Instruction::StoreAt(StoreAt {
+                array_output,
+                index,
+                ..
+            }) => vec![array_output, index],
+            Instruction::CopyAt(CopyAt {
+                array_output,
+                index,
+                input,
+            })
+            | Instruction::Insert(Insert {
+                array_output,
+                index,
+                input,
+            }) => vec![array_output, index, input],
+            Instruction::Size(Size { array_input, .. }) => vec![array_input],
+            Instruction::Resize(Resize {
+                array_input,
+                new_size,
+            }) => vec![array_input, new_size],
+            Instruction::PushBack(PushBack {
+                array_output,
+                input,
+            })
+            | Instruction::Fill(Fill {
+                array_output,
+                input,
+            }) => vec![array_output, input],
+            Instruction::Erase(Erase { array_input, index }) => vec![array_input, index],
+            Instruction::Sort(Sort { array_output })
+            | Instruction::Reverse(Reverse { array_output }) => vec![array_output],
+            Instruction::IndexOf(IndexOf {
+                array_input, input, ..
+            })
+            | Instruction::Contains(Contains {
+                array_input, input, ..
+            }) => vec![array_input, input],
+            Instruction::Slice(Slice {
+                array_input,
+                start,
+                end,
+                ..
+            }) => vec![array_input, start, end],
+            Instruction::Range(Range { start, end, .. }) => vec![start, end],
+            Instruction::Assert(Assert { input })
+            | Instruction::TypeOf(TypeOf { input, .. })
+            | Instruction::IsArray(IsArray { input, .. }) => vec![input],
+            Instruction::AssertEqual(AssertEqual { input_a, input_b }) => vec![input_a, input_b],
+            // The variable may not exist, that is what is checked
+            Instruction::AssertError(_) | Instruction::IsDefined(_) => vec![],
+        }
+    }
+
+    // Variables created or modified by the instruction
+    pub fn written_variables(&self) -> Vec<&str> {
+        match self {
+            Instruction::Free(_)
+            | Instruction::Print(_)
+            | Instruction::Call(_)
+            | Instruction::CallIf(_)
+            | Instruction::Assert(_)
+            | Instruction::AssertEqual(_)
+            | Instruction::AssertError(_) => vec![],
+            Instruction::Store(Store { dest, .. })
+            | Instruction::Copy(Copy { dest, .. })
+            | Instruction::Add(Add { dest, .. })
+            | Instruction::Substract(Substract { dest, .. })
+            | Instruction::Multiply(Multiply { dest, .. })
+            | Instruction::Divide(Divide { dest, .. })
+            | Instruction::Modulo(Modulo { dest, .. })
+            | Instruction::LogicalAnd(LogicalAnd { dest, .. })
+            | Instruction::LogicalOr(LogicalOr { dest, .. })
+            | Instruction::LogicalNot(LogicalNot { dest, .. })
+            | Instruction::CompareEqual(CompareEqual { dest, .. })
+            | Instruction::CompareDifferent(CompareDifferent { dest, .. })
+            | Instruction::CompareLess(CompareLess { dest, .. })
+            | Instruction::CompareLessOrEqual(CompareLessOrEqual { dest, .. })
+            | Instruction::CompareGreater(CompareGreater { dest, .. })
+            | Instruction::CompareGreaterOrEqual(CompareGreaterOrEqual { dest, .. })
+            | Instruction::Concat(Concat { dest, .. })
+            | Instruction::TypeOf(TypeOf { dest, .. })
+            | Instruction::IsDefined(IsDefined { dest, .. })
+            | Instruction::IsArray(IsArray { dest, .. }) => vec![dest],
+            Instruction::GetAt(GetAt { output, .. })
+            | Instruction::Size(Size { output, .. })
+            | Instruction::IndexOf(IndexOf { output, .. })
+            | Instruction::Contains(Contains { output, .. })
+            | Instruction::Slice(Slice { output, .. })
+            | Instruction::Range(Range { output, .. }) => vec![output],
+            Instruction::StoreAt(StoreAt { array_output, .. })
+            | Instruction::Sort(Sort { array_output })
+            | Instruction::Reverse(Reverse { array_output })
+            | Instruction::Fill(Fill { array_output, .. })
+            | Instruction::CopyAt(CopyAt { array_output, .. })
+            | Instruction::Insert(Insert { array_output, .. })
+            | Instruction::PushBack(PushBack { array_output, .. }) => vec![array_output],
+            Instruction::Resize(Resize { array_input, .. })
+            | Instruction::Erase(Erase { array_input, .. }) => vec![array_input],
+        }
+    }
+
+    // Routine called by the instruction, if any
+    pub fn called_routine(&self) -> Option<&str> {
+        match self {
+            Instruction::Call(Call { routine_name }) => Some(routine_name),
+            Instruction::CallIf(CallIf { routine_name, .. }) => Some(routine_name),
+            Instruction::AssertError(AssertError { routine_name }) => Some(routine_name),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -308,6 +551,99 @@ pub struct Erase {
     pub index: String,
 }
 
+// Stable, the NaN go after every other NUMBER
+#[derive(GenericInstruction, Debug, Clone)]
+pub struct Sort {
+    pub array_output: String,
+}
+
+#[derive(GenericInstruction, Debug, Clone)]
+pub struct Reverse {
+    pub array_output: String,
+}
+
+// Every element is replaced by the input
+#[derive(GenericInstruction, Debug, Clone)]
+pub struct Fill {
+    pub array_output: String,
+    pub input: String,
+}
+
+// Index of the first element equal to the input, -1 if there is none
+#[derive(GenericInstruction, Debug, Clone)]
+pub struct IndexOf {
+    pub array_input: String,
+    pub input: String,
+    pub output: String,
+}
+
+#[derive(GenericInstruction, Debug, Clone)]
+pub struct Contains {
+    pub array_input: String,
+    pub input: String,
+    pub output: String,
+}
+
+// The elements from start to end, end excluded
+#[derive(GenericInstruction, Debug, Clone)]
+pub struct Slice {
+    pub array_input: String,
+    pub start: String,
+    pub end: String,
+    pub output: String,
+}
+
+// An ARRAY_OF_INTEGER from start to end, end excluded
+#[derive(GenericInstruction, Debug, Clone)]
+pub struct Range {
+    pub start: String,
+    pub end: String,
+    pub output: String,
+}
+
+/*
+** Tests
+*/
+#[derive(GenericInstruction, Debug, Clone)]
+pub struct Assert {
+    pub input: String,
+}
+
+#[derive(GenericInstruction, Debug, Clone)]
+pub struct AssertEqual {
+    pub input_a: String,
+    pub input_b: String,
+}
+
+// Calls the routine and fails if the routine does not
+#[derive(GenericInstruction, Debug, Clone)]
+pub struct AssertError {
+    pub routine_name: String,
+}
+
+/*
+** Types
+*/
+// The name of the type as a STRING, like `ARRAY_OF_INTEGER`
+#[derive(GenericInstruction, Debug, Clone)]
+pub struct TypeOf {
+    pub input: String,
+    pub dest: String,
+}
+
+// The variable is not read, so it is not an error if it does not exist
+#[derive(GenericInstruction, Debug, Clone)]
+pub struct IsDefined {
+    pub variable: String,
+    pub dest: String,
+}
+
+#[derive(GenericInstruction, Debug, Clone)]
+pub struct IsArray {
+    pub input: String,
+    pub dest: String,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -342,6 +678,20 @@ mod tests {
         assert_eq!("INSERT", Insert::NAME);
         assert_eq!("PUSH_BACK", PushBack::NAME);
         assert_eq!("CONCAT", Concat::NAME);
+        assert_eq!("ERASE", Erase::NAME);
+        assert_eq!("SORT", Sort::NAME);
+        assert_eq!("REVERSE", Reverse::NAME);
+        assert_eq!("FILL", Fill::NAME);
+        assert_eq!("INDEX_OF", IndexOf::NAME);
+        assert_eq!("CONTAINS", Contains::NAME);
+        assert_eq!("SLICE", Slice::NAME);
+        assert_eq!("RANGE", Range::NAME);
+        assert_eq!("ASSERT", Assert::NAME);
+        assert_eq!("ASSERT_EQUAL", AssertEqual::NAME);
+        assert_eq!("ASSERT_ERROR", AssertError::NAME);
+        assert_eq!("TYPE_OF", TypeOf::NAME);
+        assert_eq!("IS_DEFINED", IsDefined::NAME);
+        assert_eq!("IS_ARRAY", IsArray::NAME);
     }
 
     #[test]
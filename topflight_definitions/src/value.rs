@@ -1,4 +1,5 @@
 use std::fmt;
+use std::rc::Rc;
 use std::str::{Chars, FromStr};
 use thiserror::Error;
 
@@ -6,17 +7,18 @@ use thiserror::Error;
 ** Public stuff
 */
 
+// Strings and arrays are shared when copied, they are only copied for real when modified (Rc::make_mut)
 #[derive(Debug, PartialEq, PartialOrd, Clone)]
 pub enum Value {
     Integer(i64),
     Number(f64),
     Char(char),
-    String(String),
+    String(Rc<String>),
     Boolean(bool),
-    ArrayOfInteger(Vec<i64>),
-    ArrayOfNumber(Vec<f64>),
-    ArrayOfString(Vec<String>),
-    ArrayOfBoolean(Vec<bool>),
+    ArrayOfInteger(Rc<Vec<i64>>),
+    ArrayOfNumber(Rc<Vec<f64>>),
+    ArrayOfString(Rc<Vec<String>>),
+    ArrayOfBoolean(Rc<Vec<bool>>),
 }
 
 impl fmt::Display for Value {
@@ -36,6 +38,86 @@ impl fmt::Display for Value {
     }
 }
 
+impl Value {
+    // Same name as the one used to write a literal of this type
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Integer(_) => "INTEGER",
+            Value::Number(_) => "NUMBER",
+            Value::Char(_) => "CHAR",
+            Value::String(_) => "STRING",
+            Value::Boolean(_) => "BOOLEAN",
+            Value::ArrayOfInteger(_) => "ARRAY_OF_INTEGER",
+            Value::ArrayOfNumber(_) => "ARRAY_OF_NUMBER",
+            Value::ArrayOfString(_) => "ARRAY_OF_STRING",
+            Value::ArrayOfBoolean(_) => "ARRAY_OF_BOOLEAN",
+        }
+    }
+
+    // The literal parsed back to this value, for example STRING("a\n"). An empty array has no
+    // literal, it gives a literal which does not parse.
+    pub fn literal(&self) -> String {
+        let elements = match self {
+            Value::Integer(i) => i.to_string(),
+            // Debug keeps the decimal point and is precise enough to be parsed back exactly
+            Value::Number(n) => format!("{:?}", n),
+            Value::Char(c) => format!("'{}'", escape(*c, '\'')),
+            Value::String(s) => string_literal(s),
+            Value::Boolean(b) => b.to_string(),
+            Value::ArrayOfInteger(v) => join(v.iter().map(i64::to_string)),
+            Value::ArrayOfNumber(v) => join(v.iter().map(|n| format!("{:?}", n))),
+            Value::ArrayOfString(v) => join(v.iter().map(|s| string_literal(s))),
+            Value::ArrayOfBoolean(v) => join(v.iter().map(bool::to_string)),
+        };
+        format!("{}({})", self.type_name(), elements)
+    }
+}
+
+// A literal as written in a STORE instruction, for example INTEGER(5)
+impl FromStr for Value {
+    type Err = ParseError;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        parse_value(&mut str.chars())
+    }
+}
+
+impl From<&str> for Value {
+    fn from(str: &str) -> Value {
+        Value::String(Rc::new(String::from(str)))
+    }
+}
+
+impl From<String> for Value {
+    fn from(string: String) -> Value {
+        Value::String(Rc::new(string))
+    }
+}
+
+impl From<Vec<i64>> for Value {
+    fn from(array: Vec<i64>) -> Value {
+        Value::ArrayOfInteger(Rc::new(array))
+    }
+}
+
+impl From<Vec<f64>> for Value {
+    fn from(array: Vec<f64>) -> Value {
+        Value::ArrayOfNumber(Rc::new(array))
+    }
+}
+
+impl From<Vec<String>> for Value {
+    fn from(array: Vec<String>) -> Value {
+        Value::ArrayOfString(Rc::new(array))
+    }
+}
+
+impl From<Vec<bool>> for Value {
+    fn from(array: Vec<bool>) -> Value {
+        Value::ArrayOfBoolean(Rc::new(array))
+    }
+}
+
 #[derive(Error, Debug, PartialEq)]
 pub enum ParseError {
     #[error("Native parsing error")]
@@ -51,16 +133,12 @@ pub fn parse_value(input: &mut Chars) -> Result<Value, ParseError> {
         "INTEGER" => Ok(Value::Integer(parse_number::<i64>(input)?.value)),
         "NUMBER" => Ok(Value::Number(parse_number::<f64>(input)?.value)),
         "BOOLEAN" => Ok(Value::Boolean(parse_boolean(input)?.value)),
-        "STRING" => Ok(Value::String(parse_string(input)?.value)),
+        "STRING" => Ok(Value::from(parse_string(input)?.value)),
         "CHAR" => Ok(Value::Char(parse_char(input)?.value)),
-        "ARRAY_OF_INTEGER" => Ok(Value::ArrayOfInteger(
-            parse_array_of_numbers::<i64>(input)?.value,
-        )),
-        "ARRAY_OF_NUMBER" => Ok(Value::ArrayOfNumber(
-            parse_array_of_numbers::<f64>(input)?.value,
-        )),
-        "ARRAY_OF_BOOLEAN" => Ok(Value::ArrayOfBoolean(parse_array_of_booleans(input)?.value)),
-        "ARRAY_OF_STRING" => Ok(Value::ArrayOfString(parse_array_of_strings(input)?.value)),
+        "ARRAY_OF_INTEGER" => Ok(Value::from(parse_array_of_numbers::<i64>(input)?.value)),
+        "ARRAY_OF_NUMBER" => Ok(Value::from(parse_array_of_numbers::<f64>(input)?.value)),
+        "ARRAY_OF_BOOLEAN" => Ok(Value::from(parse_array_of_booleans(input)?.value)),
+        "ARRAY_OF_STRING" => Ok(Value::from(parse_array_of_strings(input)?.value)),
         _ => Err(ParseError::InvalidFormat),
     }
 }
@@ -69,6 +147,28 @@ pub fn parse_value(input: &mut Chars) -> Result<Value, ParseError> {
 ** Private implementation
 */
 
+fn string_literal(string: &str) -> String {
+    let escaped: String = string.chars().map(|c| escape(c, '"')).collect();
+    format!("\"{}\"", escaped)
+}
+
+// Opposite of parse_backslash_char
+fn escape(c: char, quote: char) -> String {
+    match c {
+        '\\' => String::from("\\\\"),
+        '\0' => String::from("\\0"),
+        '\n' => String::from("\\n"),
+        '\r' => String::from("\\r"),
+        '\t' => String::from("\\t"),
+        _ if c == quote => format!("\\{}", quote),
+        _ => c.to_string(),
+    }
+}
+
+fn join(elements: impl Iterator<Item = String>) -> String {
+    elements.collect::<Vec<String>>().join(",")
+}
+
 #[derive(Debug, PartialEq)]
 struct ParseSuccess<T> {
     pub value: T,
@@ -575,4 +675,30 @@ mod tests {
         let mut input_it = input.chars();
         assert_eq!(parse_value(&mut input_it), Ok(Value::Integer(5)));
     }
+
+    #[test]
+    fn test_value_from_str() {
+        assert_eq!("INTEGER(5)".parse(), Ok(Value::Integer(5)));
+        assert_eq!("STRING(\"a b\")".parse(), Ok(Value::from("a b")));
+        assert_eq!("FLOAT(5)".parse::<Value>(), Err(ParseError::InvalidFormat));
+    }
+
+    #[test]
+    fn test_literal() {
+        let values = [
+            Value::Integer(-5),
+            Value::Number(1.0),
+            Value::Number(0.1),
+            Value::Char('\''),
+            Value::Char('\n'),
+            Value::from("a \"quoted\"\\ \t, (string)"),
+            Value::from(vec![1.5, -2.0]),
+            Value::from(vec![String::from("a,b"), String::from("")]),
+            Value::from(vec![true, false]),
+        ];
+        for value in values {
+            assert_eq!(value.literal().parse(), Ok(value));
+        }
+        assert_eq!(Value::from(vec![1, 2]).literal(), "ARRAY_OF_INTEGER(1,2)");
+    }
 }
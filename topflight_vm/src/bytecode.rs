@@ -0,0 +1,496 @@
+use topflight_definitions::*;
+
+use crate::{Memory, Routines};
+
+// Position of a variable in the memory
+pub(crate) type Slot = usize;
+
+// Instructions with every name resolved, so executing them needs no lookup by name
+pub(crate) enum Op {
+    Store {
+        dest: Slot,
+        value: Value,
+    },
+    Copy {
+        input: Slot,
+        dest: Slot,
+    },
+    Free {
+        dest: Slot,
+    },
+    Print {
+        input: Slot,
+    },
+    Call {
+        routine: Target,
+    },
+    CallIf {
+        routine: Target,
+        condition: Slot,
+    },
+    Arithmetic {
+        operator: Arithmetic,
+        a: Slot,
+        b: Slot,
+        dest: Slot,
+    },
+    Logical {
+        operator: Logical,
+        a: Slot,
+        b: Slot,
+        dest: Slot,
+    },
+    Not {
+        input: Slot,
+        dest: Slot,
+    },
+    Compare {
+        comparison: Comparison,
+        a: Slot,
+        b: Slot,
+        dest: Slot,
+    },
+    GetAt {
+        array: Slot,
+        index: Slot,
+        dest: Slot,
+    },
+    StoreAt {
+        array: Slot,
+        index: Slot,
+        value: Value,
+    },
+    CopyAt {
+        array: Slot,
+        index: Slot,
+        input: Slot,
+    },
+    Size {
+        array: Slot,
+        dest: Slot,
+    },
+    Resize {
+        array: Slot,
+        size: Slot,
+    },
+    Insert {
+        array: Slot,
+        index: Slot,
+        input: Slot,
+    },
+    PushBack {
+        array: Slot,
+        input: Slot,
+    },
+    Concat {
+        a: Slot,
+        b: Slot,
+        dest: Slot,
+    },
+    Erase {
+        array: Slot,
+        index: Slot,
+    },
+    Sort {
+        array: Slot,
+    },
+    Reverse {
+        array: Slot,
+    },
+    Fill {
+        array: Slot,
+        input: Slot,
+    },
+    IndexOf {
+        array: Slot,
+        input: Slot,
+        dest: Slot,
+    },
+    Contains {
+        array: Slot,
+        input: Slot,
+        dest: Slot,
+    },
+    Slice {
+        array: Slot,
+        start: Slot,
+        end: Slot,
+        dest: Slot,
+    },
+    Range {
+        start: Slot,
+        end: Slot,
+        dest: Slot,
+    },
+    Assert {
+        input: Slot,
+    },
+    AssertEqual {
+        a: Slot,
+        b: Slot,
+    },
+    AssertError {
+        routine: Target,
+    },
+    TypeOf {
+        input: Slot,
+        dest: Slot,
+    },
+    IsDefined {
+        variable: Slot,
+        dest: Slot,
+    },
+    IsArray {
+        input: Slot,
+        dest: Slot,
+    },
+}
+
+// A routine to call after an operation
+pub(crate) struct RoutineCall {
+    pub routine: usize,
+    // Called by ASSERT_ERROR, an error in it is expected
+    pub expects_error: bool,
+}
+
+impl RoutineCall {
+    pub fn new(routine: usize) -> RoutineCall {
+        RoutineCall {
+            routine,
+            expects_error: false,
+        }
+    }
+}
+
+pub(crate) enum Target {
+    Index(usize),
+    // Only possible at the top level, the calls inside a routine are registered with it
+    Unknown(String),
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Arithmetic {
+    Add,
+    Substract,
+    Multiply,
+    Divide,
+    Modulo,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Logical {
+    And,
+    Or,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Comparison {
+    Equal,
+    Different,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+pub(crate) fn compile(instruction: &Instruction, memory: &mut Memory, routines: &Routines) -> Op {
+    let mut slot = |name: &String| memory.slot(name);
+    let target = |name: &String| match routines.index_of(name) {
+        Some(index) => Target::Index(index),
+        None => Target::Unknown(name.clone()),
+    };
+
+    match instruction {
+        Instruction::Store(Store { dest, value }) => Op::Store {
+            dest: slot(dest),
+            value: value.clone(),
+        },
+        Instruction::Copy(Copy { input, dest }) => Op::Copy {
+            input: slot(input),
+            dest: slot(dest),
+        },
+        Instruction::Free(Free { dest }) => Op::Free { dest: slot(dest) },
+        Instruction::Print(Print { input }) => Op::Print { input: slot(input) },
+        Instruction::Call(Call { routine_name }) => Op::Call {
+            routine: target(routine_name),
+        },
+        Instruction::CallIf(CallIf {
+            routine_name,
+            boolean_input_name,
+        }) => Op::CallIf {
+            routine: target(routine_name),
+            condition: slot(boolean_input_name),
+        },
+        Instruction::Add(Add {
+            input_a,
+            input_b,
+            dest,
+        }) => Op::Arithmetic {
+            operator: Arithmetic::Add,
+            a: slot(input_a),
+            b: slot(input_b),
+            dest: slot(dest),
+        },
+        Instruction::Substract(Substract {
+            input_a,
+            input_b,
+            dest,
+        }) => Op::Arithmetic {
+            operator: Arithmetic::Substract,
+            a: slot(input_a),
+            b: slot(input_b),
+            dest: slot(dest),
+        },
+        Instruction::Multiply(Multiply {
+            input_a,
+            input_b,
+            dest,
+        }) => Op::Arithmetic {
+            operator: Arithmetic::Multiply,
+            a: slot(input_a),
+            b: slot(input_b),
+            dest: slot(dest),
+        },
+        Instruction::Divide(Divide {
+            input_a,
+            input_b,
+            dest,
+        }) => Op::Arithmetic {
+            operator: Arithmetic::Divide,
+            a: slot(input_a),
+            b: slot(input_b),
+            dest: slot(dest),
+        },
+        Instruction::Modulo(Modulo {
+            input_a,
+            input_b,
+            dest,
+        }) => Op::Arithmetic {
+            operator: Arithmetic::Modulo,
+            a: slot(input_a),
+            b: slot(input_b),
+            dest: slot(dest),
+        },
+        Instruction::LogicalAnd(LogicalAnd {
+            input_a,
+            input_b,
+            dest,
+        }) => Op::Logical {
+            operator: Logical::And,
+            a: slot(input_a),
+            b: slot(input_b),
+            dest: slot(dest),
+        },
+        Instruction::LogicalOr(LogicalOr {
+            input_a,
+            input_b,
+            dest,
+        }) => Op::Logical {
+            operator: Logical::Or,
+            a: slot(input_a),
+            b: slot(input_b),
+            dest: slot(dest),
+        },
+        Instruction::LogicalNot(LogicalNot { input, dest }) => Op::Not {
+            input: slot(input),
+            dest: slot(dest),
+        },
+        Instruction::CompareEqual(CompareEqual {
+            input_a,
+            input_b,
+            dest,
+        }) => Op::Compare {
+            comparison: Comparison::Equal,
+            a: slot(input_a),
+            b: slot(input_b),
+            dest: slot(dest),
+        },
+        Instruction::CompareDifferent(CompareDifferent {
+            input_a,
+            input_b,
+            dest,
+        }) => Op::Compare {
+            comparison: Comparison::Different,
+            a: slot(input_a),
+            b: slot(input_b),
+            dest: slot(dest),
+        },
+        Instruction::CompareLess(CompareLess {
+            input_a,
+            input_b,
+            dest,
+        }) => Op::Compare {
+            comparison: Comparison::Less,
+            a: slot(input_a),
+            b: slot(input_b),
+            dest: slot(dest),
+        },
+        Instruction::CompareLessOrEqual(CompareLessOrEqual {
+            input_a,
+            input_b,
+            dest,
+        }) => Op::Compare {
+            comparison: Comparison::LessOrEqual,
+            a: slot(input_a),
+            b: slot(input_b),
+            dest: slot(dest),
+        },
+        Instruction::CompareGreater(CompareGreater {
+            input_a,
+            input_b,
+            dest,
+        }) => Op::Compare {
+            comparison: Comparison::Greater,
+            a: slot(input_a),
+            b: slot(input_b),
+            dest: slot(dest),
+        },
+        Instruction::CompareGreaterOrEqual(CompareGreaterOrEqual {
+            input_a,
+            input_b,
+            dest,
+        }) => Op::Compare {
+            comparison: Comparison::GreaterOrEqual,
+            a: slot(input_a),
+            b: slot(input_b),
+            dest: slot(dest),
+        },
+        Instruction::GetAt(GetAt {
+            array_input,
+            index,
+            output,
+        }) => Op::GetAt {
+            array: slot(array_input),
+            index: slot(index),
+            dest: slot(output),
+        },
+        Instruction::StoreAt(StoreAt {
+            array_output,
+            index,
+            value,
+        }) => Op::StoreAt {
+            array: slot(array_output),
+            index: slot(index),
+            value: value.clone(),
+        },
+        Instruction::CopyAt(CopyAt {
+            array_output,
+            index,
+            input,
+        }) => Op::CopyAt {
+            array: slot(array_output),
+            index: slot(index),
+            input: slot(input),
+        },
+        Instruction::Size(Size {
+            array_input,
+            output,
+        }) => Op::Size {
+            array: slot(array_input),
+            dest: slot(output),
+        },
+        Instruction::Resize(Resize {
+            array_input,
+            new_size,
+        }) => Op::Resize {
+            array: slot(array_input),
+            size: slot(new_size),
+        },
+        Instruction::Insert(Insert {
+            array_output,
+            index,
+            input,
+        }) => Op::Insert {
+            array: slot(array_output),
+            index: slot(index),
+            input: slot(input),
+        },
+        Instruction::PushBack(PushBack {
+            array_output,
+            input,
+        }) => Op::PushBack {
+            array: slot(array_output),
+            input: slot(input),
+        },
+        Instruction::Concat(Concat {
+            input_a,
+            input_b,
+            dest,
+        }) => Op::Concat {
+            a: slot(input_a),
+            b: slot(input_b),
+            dest: slot(dest),
+        },
+        Instruction::Erase(Erase { array_input, index }) => Op::Erase {
+            array: slot(array_input),
+            index: slot(index),
+        },
+        Instruction::Sort(Sort { array_output }) => Op::Sort {
+            array: slot(array_output),
+        },
+        Instruction::Reverse(Reverse { array_output }) => Op::Reverse {
+            array: slot(array_output),
+        },
+        Instruction::Fill(Fill {
+            array_output,
+            input,
+        }) => Op::Fill {
+            array: slot(array_output),
+            input: slot(input),
+        },
+        Instruction::IndexOf(IndexOf {
+            array_input,
+            input,
+            output,
+        }) => Op::IndexOf {
+            array: slot(array_input),
+            input: slot(input),
+            dest: slot(output),
+        },
+        Instruction::Contains(Contains {
+            array_input,
+            input,
+            output,
+        }) => Op::Contains {
+            array: slot(array_input),
+            input: slot(input),
+            dest: slot(output),
+        },
+        Instruction::Slice(Slice {
+            array_input,
+            start,
+            end,
+            output,
+        }) => Op::Slice {
+            array: slot(array_input),
+            start: slot(start),
+            end: slot(end),
+            dest: slot(output),
+        },
+        Instruction::Range(Range { start, end, output }) => Op::Range {
+            start: slot(start),
+            end: slot(end),
+            dest: slot(output),
+        },
+        Instruction::Assert(Assert { input }) => Op::Assert { input: slot(input) },
+        Instruction::AssertEqual(AssertEqual { input_a, input_b }) => Op::AssertEqual {
+            a: slot(input_a),
+            b: slot(input_b),
+        },
+        Instruction::AssertError(AssertError { routine_name }) => Op::AssertError {
+            routine: target(routine_name),
+        },
+        Instruction::TypeOf(TypeOf { input, dest }) => Op::TypeOf {
+            input: slot(input),
+            dest: slot(dest),
+        },
+        Instruction::IsDefined(IsDefined { variable, dest }) => Op::IsDefined {
+            variable: slot(variable),
+            dest: slot(dest),
+        },
+        Instruction::IsArray(IsArray { input, dest }) => Op::IsArray {
+            input: slot(input),
+            dest: slot(dest),
+        },
+    }
+}
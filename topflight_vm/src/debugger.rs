@@ -0,0 +1,445 @@
+use topflight_definitions::Instruction;
+
+use crate::execution::Execution;
+use crate::{Limits, Memory, Routines, VMError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Breakpoint {
+    // Stop before the instruction at this line of the source
+    Line(usize),
+    // Stop when the routine is called
+    Routine(String),
+}
+
+// Where an instruction is, inside a routine or at the top level of the program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub routine: Option<String>,
+    // Position of the instruction in the routine, or in the program at the top level
+    pub index: usize,
+    // Source line, 0 when unknown
+    pub line: usize,
+}
+
+// Why the debugger gave the control back
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pause {
+    Step,
+    Breakpoint(Breakpoint),
+    Finished,
+}
+
+// Executes a program step by step. Between two calls the memory can be read and modified, the
+// same memory and routines must be given each time.
+pub struct Debugger {
+    program: Vec<(usize, Instruction)>,
+    // Next top level instruction to start
+    next_instruction: usize,
+    // Top level instruction being executed
+    execution: Option<Execution>,
+    // The execution is kept after an error to know where it happened
+    failed: bool,
+    breakpoints: Vec<Breakpoint>,
+}
+
+impl Debugger {
+    // Takes the top level instructions with their line
+    pub fn new(program: Vec<(usize, Instruction)>) -> Debugger {
+        Debugger {
+            program,
+            next_instruction: 0,
+            execution: None,
+            failed: false,
+            breakpoints: Vec::new(),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        if !self.breakpoints.contains(&breakpoint) {
+            self.breakpoints.push(breakpoint);
+        }
+    }
+
+    // Returns false if there was no such breakpoint
+    pub fn remove_breakpoint(&mut self, breakpoint: &Breakpoint) -> bool {
+        let count = self.breakpoints.len();
+        self.breakpoints.retain(|other| other != breakpoint);
+        self.breakpoints.len() != count
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.failed || (self.execution.is_none() && self.next_instruction >= self.program.len())
+    }
+
+    // The next instruction to execute, or the one that failed. None once the program is finished.
+    pub fn location(&self, routines: &Routines) -> Option<Location> {
+        match &self.execution {
+            Some(_) => self.call_stack(routines).pop(),
+            None => self
+                .program
+                .get(self.next_instruction)
+                .map(|(line, _)| Location {
+                    routine: None,
+                    index: self.next_instruction,
+                    line: *line,
+                }),
+        }
+    }
+
+    // The top level instruction first, then the calls being executed, then the next instruction
+    pub fn call_stack(&self, routines: &Routines) -> Vec<Location> {
+        let execution = match &self.execution {
+            Some(execution) => execution,
+            None => return self.location(routines).into_iter().collect(),
+        };
+
+        let last = execution.call_stack.len() - 1;
+        execution
+            .call_stack
+            .iter()
+            .enumerate()
+            .map(|(depth, frame)| {
+                // The outer frames are already past their call
+                let index = match depth == last && !self.failed {
+                    true => frame.position,
+                    false => frame.position - 1,
+                };
+                match frame.routine {
+                    Some(routine) => Location {
+                        routine: Some(routines.name_of(routine).to_string()),
+                        index,
+                        line: routines.line_of(routine, index),
+                    },
+                    None => Location {
+                        routine: None,
+                        index: self.next_instruction - 1,
+                        line: execution.line,
+                    },
+                }
+            })
+            .collect()
+    }
+
+    // Execute one instruction, entering the called routines
+    pub fn step_into(
+        &mut self,
+        memory: &mut Memory,
+        routines: &Routines,
+        output: &mut String,
+        limits: &mut Limits,
+    ) -> Result<Pause, VMError> {
+        self.step(memory, routines, output, limits)?;
+        Ok(match self.is_finished() {
+            true => Pause::Finished,
+            false => Pause::Step,
+        })
+    }
+
+    // Execute one instruction of the current routine, the called routines are run entirely
+    // unless they reach a breakpoint
+    pub fn step_over(
+        &mut self,
+        memory: &mut Memory,
+        routines: &Routines,
+        output: &mut String,
+        limits: &mut Limits,
+    ) -> Result<Pause, VMError> {
+        let depth = self.depth();
+        self.run_until(memory, routines, output, limits, |debugger| {
+            debugger.depth() <= depth
+        })
+    }
+
+    // Run until the current routine returns
+    pub fn step_out(
+        &mut self,
+        memory: &mut Memory,
+        routines: &Routines,
+        output: &mut String,
+        limits: &mut Limits,
+    ) -> Result<Pause, VMError> {
+        let depth = self.depth();
+        if depth == 0 {
+            return self.step_over(memory, routines, output, limits);
+        }
+        self.run_until(memory, routines, output, limits, |debugger| {
+            debugger.depth() < depth
+        })
+    }
+
+    // Run until a breakpoint or the end of the program
+    pub fn resume(
+        &mut self,
+        memory: &mut Memory,
+        routines: &Routines,
+        output: &mut String,
+        limits: &mut Limits,
+    ) -> Result<Pause, VMError> {
+        // A breakpoint on the very first instruction stops the program before it starts
+        if self.next_instruction == 0 && self.execution.is_none() {
+            if let Some(breakpoint) = self.breakpoint_hit(routines) {
+                return Ok(Pause::Breakpoint(breakpoint.clone()));
+            }
+        }
+        self.run_until(memory, routines, output, limits, |_| false)
+    }
+
+    fn run_until(
+        &mut self,
+        memory: &mut Memory,
+        routines: &Routines,
+        output: &mut String,
+        limits: &mut Limits,
+        done: impl Fn(&Debugger) -> bool,
+    ) -> Result<Pause, VMError> {
+        loop {
+            self.step(memory, routines, output, limits)?;
+            if self.is_finished() {
+                return Ok(Pause::Finished);
+            }
+            if let Some(breakpoint) = self.breakpoint_hit(routines) {
+                return Ok(Pause::Breakpoint(breakpoint.clone()));
+            }
+            if done(self) {
+                return Ok(Pause::Step);
+            }
+        }
+    }
+
+    fn step(
+        &mut self,
+        memory: &mut Memory,
+        routines: &Routines,
+        output: &mut String,
+        limits: &mut Limits,
+    ) -> Result<(), VMError> {
+        if self.failed {
+            return Ok(());
+        }
+        let execution = match &mut self.execution {
+            Some(execution) => execution,
+            None => match self.program.get(self.next_instruction) {
+                Some((line, instruction)) => {
+                    self.next_instruction += 1;
+                    self.execution
+                        .insert(Execution::new(instruction, *line, memory, routines))
+                }
+                None => return Ok(()),
+            },
+        };
+
+        let result = execution.step(memory, routines, output, limits);
+        if result.as_ref().is_err_and(|error| !error.is_interruption()) {
+            // The program can't go on after an error, but it can after an interruption
+            self.failed = true;
+        } else if execution.is_finished() {
+            self.execution = None;
+        }
+        result
+    }
+
+    fn depth(&self) -> usize {
+        self.execution.as_ref().map_or(0, Execution::depth)
+    }
+
+    // Checked before every instruction so it must not allocate
+    fn breakpoint_hit(&self, routines: &Routines) -> Option<&Breakpoint> {
+        if self.breakpoints.is_empty() {
+            return None;
+        }
+        let (routine, index, line) = match &self.execution {
+            Some(execution) => {
+                let frame = execution.call_stack.last()?;
+                match frame.routine {
+                    Some(routine) => (
+                        Some(routine),
+                        frame.position,
+                        routines.line_of(routine, frame.position),
+                    ),
+                    None => (None, 0, execution.line),
+                }
+            }
+            None => (None, 0, self.program.get(self.next_instruction)?.0),
+        };
+
+        self.breakpoints.iter().find(|breakpoint| match breakpoint {
+            Breakpoint::Line(breakpoint_line) => *breakpoint_line == line && line != 0,
+            Breakpoint::Routine(name) => {
+                index == 0 && routine.is_some_and(|routine| routines.name_of(routine) == name)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use topflight_definitions::{Routine, Value};
+
+    fn routine(name: &str, first_line: usize, lines: &[&str]) -> Routine {
+        let mut routine = Routine::new(String::from(name));
+        for (offset, line) in lines.iter().enumerate() {
+            let instruction = Instruction::parse(line).expect("Valid instruction");
+            routine.instructions.push(instruction);
+            routine.lines.push(first_line + offset);
+        }
+        routine
+    }
+
+    // Lines 1 to 4 are the routines, the program starts at line 6
+    fn setup() -> (Debugger, Memory, Routines) {
+        let mut routines = Routines::new();
+        routines.insert(
+            String::from("inner"),
+            routine("inner", 1, &["ADD i one i", "PRINT i"]),
+        );
+        routines.insert(
+            String::from("outer"),
+            routine("outer", 3, &["CALL inner", "CALL inner"]),
+        );
+        let program = ["STORE i INTEGER(0)", "STORE one INTEGER(1)", "CALL outer"]
+            .iter()
+            .enumerate()
+            .map(|(offset, line)| (6 + offset, Instruction::parse(line).unwrap()))
+            .collect();
+        (Debugger::new(program), Memory::default(), routines)
+    }
+
+    fn line(debugger: &Debugger, routines: &Routines) -> usize {
+        debugger.location(routines).expect("Not finished").line
+    }
+
+    #[test]
+    fn test_step_into_over_and_out() {
+        let (mut debugger, mut memory, routines) = setup();
+        let mut output = String::new();
+        let mut limits = Limits::default();
+
+        assert_eq!(line(&debugger, &routines), 6);
+        for _ in 0..3 {
+            debugger
+                .step_into(&mut memory, &routines, &mut output, &mut limits)
+                .unwrap();
+        }
+        // Inside `outer`, on its first call
+        let location = debugger.location(&routines).unwrap();
+        assert_eq!(location.routine.as_deref(), Some("outer"));
+        assert_eq!((location.index, location.line), (0, 3));
+
+        let pause = debugger
+            .step_over(&mut memory, &routines, &mut output, &mut limits)
+            .unwrap();
+        assert_eq!(pause, Pause::Step);
+        assert_eq!(line(&debugger, &routines), 4);
+        assert_eq!(output, "1");
+
+        debugger
+            .step_into(&mut memory, &routines, &mut output, &mut limits)
+            .unwrap();
+        let stack = debugger.call_stack(&routines);
+        let lines: Vec<usize> = stack.iter().map(|location| location.line).collect();
+        assert_eq!(lines, vec![8, 4, 1]);
+
+        debugger
+            .step_out(&mut memory, &routines, &mut output, &mut limits)
+            .unwrap();
+        assert_eq!(output, "12");
+        let pause = debugger
+            .step_out(&mut memory, &routines, &mut output, &mut limits)
+            .unwrap();
+        assert_eq!(pause, Pause::Finished);
+        assert!(debugger.location(&routines).is_none());
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let (mut debugger, mut memory, routines) = setup();
+        let mut output = String::new();
+        let mut limits = Limits::default();
+        debugger.add_breakpoint(Breakpoint::Routine(String::from("inner")));
+        debugger.add_breakpoint(Breakpoint::Line(2));
+
+        let mut pauses = Vec::new();
+        loop {
+            let pause = debugger
+                .resume(&mut memory, &routines, &mut output, &mut limits)
+                .unwrap();
+            if pause == Pause::Finished {
+                break;
+            }
+            pauses.push((pause, line(&debugger, &routines)));
+        }
+
+        let routine_hit = (
+            Pause::Breakpoint(Breakpoint::Routine(String::from("inner"))),
+            1,
+        );
+        let line_hit = (Pause::Breakpoint(Breakpoint::Line(2)), 2);
+        assert_eq!(
+            pauses,
+            vec![routine_hit.clone(), line_hit.clone(), routine_hit, line_hit]
+        );
+        assert!(debugger.remove_breakpoint(&Breakpoint::Line(2)));
+        assert!(!debugger.remove_breakpoint(&Breakpoint::Line(2)));
+    }
+
+    #[test]
+    fn test_modify_memory_while_paused() {
+        let (mut debugger, mut memory, routines) = setup();
+        let mut output = String::new();
+        let mut limits = Limits::default();
+        debugger.add_breakpoint(Breakpoint::Line(2));
+
+        debugger
+            .resume(&mut memory, &routines, &mut output, &mut limits)
+            .unwrap();
+        memory.store("i", Value::Integer(41));
+        debugger
+            .resume(&mut memory, &routines, &mut output, &mut limits)
+            .unwrap();
+        assert_eq!(output, "41");
+    }
+
+    #[test]
+    fn test_error_ends_the_program() {
+        let program = vec![(1, Instruction::parse("PRINT missing").unwrap())];
+        let mut debugger = Debugger::new(program);
+        let error = debugger.step_into(
+            &mut Memory::default(),
+            &Routines::new(),
+            &mut String::new(),
+            &mut Limits::default(),
+        );
+        assert!(error.is_err());
+        assert!(debugger.is_finished());
+        assert_eq!(debugger.location(&Routines::new()).unwrap().line, 1);
+    }
+
+    #[test]
+    fn test_resume_after_step_limit() {
+        let (mut debugger, mut memory, routines) = setup();
+        let mut output = String::new();
+        // Stops inside `inner`, right before the first PRINT
+        let mut limits = Limits {
+            max_steps: Some(5),
+            ..Limits::default()
+        };
+
+        let error = debugger.resume(&mut memory, &routines, &mut output, &mut limits);
+        assert!(matches!(error, Err(VMError::StepLimitExceeded(5))));
+        assert!(!debugger.is_finished());
+        assert_eq!(line(&debugger, &routines), 2);
+        assert_eq!(output, "");
+
+        limits.max_steps = None;
+        let pause = debugger
+            .resume(&mut memory, &routines, &mut output, &mut limits)
+            .unwrap();
+        assert_eq!(pause, Pause::Finished);
+        assert_eq!(output, "12");
+    }
+}
@@ -0,0 +1,181 @@
+use std::fmt;
+use thiserror::Error;
+use topflight_definitions::Value;
+
+// Number of elements of an array, or characters of a string, shown in an error
+const PREVIEW_SIZE: usize = 8;
+
+// A value used by a failed instruction. Only the start of the value is kept, it could be huge.
+// Boxed to keep VMError small, it is returned by every instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Operand(Box<Described>);
+
+#[derive(Debug, Clone, PartialEq)]
+struct Described {
+    name: Option<String>,
+    type_name: &'static str,
+    preview: String,
+}
+
+impl Operand {
+    pub fn new(name: Option<&str>, value: &Value) -> Operand {
+        Operand(Box::new(Described {
+            name: name.map(String::from),
+            type_name: value.type_name(),
+            preview: preview(value),
+        }))
+    }
+
+    // None for a literal
+    pub fn name(&self) -> Option<&str> {
+        self.0.name.as_deref()
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        self.0.type_name
+    }
+
+    // Written as a literal, shortened with `...`
+    pub fn preview(&self) -> &str {
+        &self.0.preview
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "`{}` = {}", name, self.preview()),
+            None => write!(f, "{}", self.preview()),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum VMError {
+    #[error("Variable `{0}` does not exist")]
+    VariableDoesNotExist(String),
+    #[error("Routine `{0}` does not exist")]
+    RoutineDoesNotExist(String),
+    #[error("Expected a BOOLEAN but {0}")]
+    ExpectedBoolean(Operand),
+    #[error("Mismatching types, {0} and {1}")]
+    MismatchingTypes(Operand, Operand),
+    #[error("Expected an array but {0}")]
+    ExpectedArray(Operand),
+    #[error("Expected a STRING or an array but {0}")]
+    ExpectedConcatenable(Operand),
+    #[error("Expected an INTEGER but {0}")]
+    ExpectedInteger(Operand),
+    #[error("Expected two INTEGER or two NUMBER but {0} and {1}")]
+    ExpectedArithmeticTypes(Operand, Operand),
+    #[error("Index {index} is out of bound, {array} has {array_size} elements")]
+    IndexOutOfBound {
+        array: Operand,
+        array_size: usize,
+        index: Operand,
+    },
+    #[error("An index must be an INTEGER but {0}")]
+    NonIntegerIndex(Operand),
+    #[error("Error while adding something to the output buffer")]
+    OutputBufferError(#[from] std::fmt::Error),
+    #[error("The limit of {0} executed instructions is reached")]
+    StepLimitExceeded(u64),
+    #[error("The execution was cancelled")]
+    Cancelled,
+    #[error("The execution took too much time")]
+    Timeout,
+    #[error("The memory limit of {max_size} bytes is reached, {needed} bytes would be used")]
+    MemoryLimitExceeded { max_size: usize, needed: usize },
+    #[error("Cannot allocate an array of {0} elements")]
+    AllocationFailed(usize),
+    #[error(
+        "Calling `{routine}` goes over the maximum call depth of {max_call_depth}, the last calls are: {}",
+        .stack.join(" -> ")
+    )]
+    CallDepthExceeded {
+        routine: String,
+        max_call_depth: usize,
+        // Only the innermost routines, the whole stack could be huge
+        stack: Vec<String>,
+    },
+    #[error("Assertion failed: {0}")]
+    AssertionFailed(String),
+    #[error("Division by zero, {0}")]
+    DivisionByZero(Operand),
+    #[error("The result of `{instruction}` with {a} and {b} is too big for an INTEGER")]
+    IntegerOverflow {
+        instruction: &'static str,
+        a: Operand,
+        b: Operand,
+    },
+}
+
+impl VMError {
+    // Errors coming from the limits of the execution, not from the program, ASSERT_ERROR can't
+    // catch them
+    pub fn is_interruption(&self) -> bool {
+        matches!(
+            self,
+            VMError::StepLimitExceeded(_) | VMError::Cancelled | VMError::Timeout
+        )
+    }
+}
+
+fn preview(value: &Value) -> String {
+    let shorten = |string: &String| string.chars().take(PREVIEW_SIZE).collect::<String>();
+    let shortened = match value {
+        Value::String(string) if string.chars().count() > PREVIEW_SIZE => {
+            // The `...` goes after the quote
+            let mut literal = Value::from(shorten(string)).literal();
+            literal.insert_str(literal.len() - 1, "...");
+            return literal;
+        }
+        Value::ArrayOfInteger(array) if array.len() > PREVIEW_SIZE => {
+            Value::from(array[..PREVIEW_SIZE].to_vec())
+        }
+        Value::ArrayOfNumber(array) if array.len() > PREVIEW_SIZE => {
+            Value::from(array[..PREVIEW_SIZE].to_vec())
+        }
+        Value::ArrayOfBoolean(array) if array.len() > PREVIEW_SIZE => {
+            Value::from(array[..PREVIEW_SIZE].to_vec())
+        }
+        Value::ArrayOfString(array) => {
+            let strings: Vec<String> = array.iter().take(PREVIEW_SIZE).map(shorten).collect();
+            let is_shortened = array.len() > PREVIEW_SIZE
+                || array
+                    .iter()
+                    .any(|string| string.chars().count() > PREVIEW_SIZE);
+            if !is_shortened {
+                return value.literal();
+            }
+            Value::from(strings)
+        }
+        _ => return value.literal(),
+    };
+    let mut literal = shortened.literal();
+    literal.insert_str(literal.len() - 1, ",...");
+    literal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preview() {
+        let operand = Operand::new(Some("i"), &Value::Integer(5));
+        assert_eq!(operand.to_string(), "`i` = INTEGER(5)");
+        assert_eq!(operand.type_name(), "INTEGER");
+        let operand = Operand::new(None, &Value::from((0..100).collect::<Vec<i64>>()));
+        assert_eq!(operand.to_string(), "ARRAY_OF_INTEGER(0,1,2,3,4,5,6,7,...)");
+        assert_eq!(
+            preview(&Value::from("a long string")),
+            "STRING(\"a long s\"...)"
+        );
+        assert_eq!(
+            preview(&Value::from(vec![String::from("a long string")])),
+            "ARRAY_OF_STRING(\"a long s\",...)"
+        );
+        assert_eq!(preview(&Value::from("short")), "STRING(\"short\")");
+    }
+}
@@ -0,0 +1,237 @@
+use std::rc::Rc;
+use topflight_definitions::Instruction;
+
+use crate::bytecode::{self, Op, RoutineCall};
+use crate::{execute_op, Event, Limits, Memory, Observer, Routines, VMError};
+
+// Number of routines listed in the error when the call stack is too deep
+const CALL_DEPTH_ERROR_STACK_SIZE: usize = 10;
+
+// A routine being executed and the position of its next instruction
+pub(crate) struct Frame {
+    // None for the top level instruction
+    pub routine: Option<usize>,
+    pub code: Rc<[Op]>,
+    pub position: usize,
+    // Called by ASSERT_ERROR, it must fail
+    pub expects_error: bool,
+}
+
+// A top level instruction being executed, the routines it calls are run in a loop with their own
+// stack instead of recursively so the execution can be stopped and resumed between two operations
+pub(crate) struct Execution {
+    // Line of the top level instruction
+    pub line: usize,
+    pub call_stack: Vec<Frame>,
+}
+
+impl Execution {
+    pub fn new(
+        instruction: &Instruction,
+        line: usize,
+        memory: &mut Memory,
+        routines: &Routines,
+    ) -> Execution {
+        let op = bytecode::compile(instruction, memory, routines);
+        Execution {
+            line,
+            call_stack: vec![Frame {
+                routine: None,
+                code: Rc::from([op]),
+                position: 0,
+                expects_error: false,
+            }],
+        }
+    }
+
+    // Run until the end
+    pub fn run(
+        &mut self,
+        memory: &mut Memory,
+        routines: &Routines,
+        output: &mut String,
+        limits: &mut Limits,
+    ) -> Result<(), VMError> {
+        'frames: while let Some(mut frame) = self.call_stack.pop() {
+            while let Some(op) = frame.code.get(frame.position) {
+                // Kept before the operation so the execution can be resumed after an interruption
+                if let Err(error) = limits.count_step() {
+                    self.call_stack.push(frame);
+                    return Err(error);
+                }
+                frame.position += 1;
+                match execute_op(op, memory, output) {
+                    Ok(None) => (),
+                    Ok(Some(call)) => {
+                        self.call_stack.push(frame);
+                        if let Err(error) = self.call(call, memory, routines, limits) {
+                            self.recover(error)?;
+                        }
+                        continue 'frames;
+                    }
+                    Err(error) => {
+                        self.call_stack.push(frame);
+                        self.recover(error)?;
+                        continue 'frames;
+                    }
+                }
+            }
+            if frame.expects_error {
+                self.recover(did_not_fail(&frame, routines))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Run until the end, the observer is notified around each instruction. The top level
+    // instruction must be the one given to `new`.
+    pub fn run_observed(
+        &mut self,
+        instruction: &Instruction,
+        memory: &mut Memory,
+        routines: &Routines,
+        output: &mut String,
+        limits: &mut Limits,
+        observer: &mut dyn Observer,
+    ) -> Result<(), VMError> {
+        // Names of the routines of the frames, kept in step with them. A step only pushes a
+        // frame on top before popping some, so only the end of the names changes.
+        let mut call_stack: Vec<&str> = Vec::new();
+        while let Some(frame) = self.call_stack.last() {
+            let (instruction, line) = match frame.routine {
+                Some(routine) => (
+                    routines.instruction(routine, frame.position),
+                    routines.line_of(routine, frame.position),
+                ),
+                None => (instruction, self.line),
+            };
+            call_stack.truncate(self.depth());
+            call_stack.extend(
+                self.call_stack[call_stack.len() + 1..]
+                    .iter()
+                    .filter_map(|frame| frame.routine)
+                    .map(|routine| routines.name_of(routine)),
+            );
+            let event = Event {
+                instruction,
+                line,
+                call_stack: &call_stack,
+            };
+
+            observer.before(&event, memory);
+            if let Err(error) = self.step(memory, routines, output, limits) {
+                observer.failed(&event, memory, &error);
+                return Err(error);
+            }
+            observer.after(&event, memory);
+        }
+        Ok(())
+    }
+
+    // Execute only the next operation
+    pub fn step(
+        &mut self,
+        memory: &mut Memory,
+        routines: &Routines,
+        output: &mut String,
+        limits: &mut Limits,
+    ) -> Result<(), VMError> {
+        if let Some(frame) = self.call_stack.last_mut() {
+            if let Some(op) = frame.code.get(frame.position) {
+                // An interruption leaves the execution before the operation so it can be resumed
+                limits.count_step()?;
+                // Moved before executing so the position is past the operation even if it fails
+                frame.position += 1;
+                let result = match execute_op(op, memory, output) {
+                    Ok(Some(call)) => self.call(call, memory, routines, limits),
+                    Ok(None) => Ok(()),
+                    Err(error) => Err(error),
+                };
+                if let Err(error) = result {
+                    self.recover(error)?;
+                }
+            }
+        }
+
+        // The finished routines are left right away so the execution is always before an operation
+        while self
+            .call_stack
+            .last()
+            .is_some_and(|frame| frame.position >= frame.code.len())
+        {
+            let frame = self.call_stack.pop().expect("Checked by the loop");
+            if frame.expects_error {
+                self.recover(did_not_fail(&frame, routines))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.call_stack.is_empty()
+    }
+
+    // Number of routines being executed
+    pub fn depth(&self) -> usize {
+        self.call_stack.len().saturating_sub(1)
+    }
+
+    // An error is caught by the innermost routine called by ASSERT_ERROR, the execution goes on
+    // after the ASSERT_ERROR. The interruptions are never caught.
+    fn recover(&mut self, error: VMError) -> Result<(), VMError> {
+        if error.is_interruption() {
+            return Err(error);
+        }
+        match self
+            .call_stack
+            .iter()
+            .rposition(|frame| frame.expects_error)
+        {
+            Some(index) => {
+                self.call_stack.truncate(index);
+                Ok(())
+            }
+            None => Err(error),
+        }
+    }
+
+    fn call(
+        &mut self,
+        call: RoutineCall,
+        memory: &mut Memory,
+        routines: &Routines,
+        limits: &Limits,
+    ) -> Result<(), VMError> {
+        if let Some(max_call_depth) = limits.max_call_depth {
+            if self.depth() >= max_call_depth {
+                let start = self.call_stack.len() - CALL_DEPTH_ERROR_STACK_SIZE.min(self.depth());
+                return Err(VMError::CallDepthExceeded {
+                    routine: routines.name_of(call.routine).to_string(),
+                    max_call_depth,
+                    stack: self.call_stack[start..]
+                        .iter()
+                        .filter_map(|frame| frame.routine)
+                        .map(|routine| routines.name_of(routine).to_string())
+                        .collect(),
+                });
+            }
+        }
+
+        let code = routines.code(call.routine, memory)?;
+        self.call_stack.push(Frame {
+            routine: Some(call.routine),
+            code,
+            position: 0,
+            expects_error: call.expects_error,
+        });
+        Ok(())
+    }
+}
+
+// Error of a routine called by ASSERT_ERROR which finished without error
+fn did_not_fail(frame: &Frame, routines: &Routines) -> VMError {
+    let name = frame
+        .routine
+        .map_or("", |routine| routines.name_of(routine));
+    VMError::AssertionFailed(format!("`{}` did not fail", name))
+}
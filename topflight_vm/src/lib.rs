@@ -1,80 +1,432 @@
 use std::collections::HashMap;
 use std::fmt::Write;
-use thiserror::Error;
+use std::mem::size_of;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 use topflight_definitions::*;
 
-#[derive(Default)]
+mod bytecode;
+mod debugger;
+mod error;
+mod execution;
+mod observer;
+mod profiler;
+mod routines;
+
+use bytecode::{Arithmetic, Comparison, Logical, Op, RoutineCall, Slot, Target};
+pub use debugger::{Breakpoint, Debugger, Location, Pause};
+pub use error::{Operand, VMError};
+use execution::Execution;
+pub use observer::{Event, Observer};
+pub use profiler::Profiler;
+pub use routines::Routines;
+
+// Variables are given a slot the first time their name is seen, the values are then accessed by slot
 pub struct Memory {
-    values: HashMap<String, Value>,
+    id: usize,
+    slots: HashMap<String, Slot>,
+    names: Vec<String>,
+    values: Vec<Option<Value>>,
+    // Approximate number of bytes used by the values, see value_size
+    size: usize,
+    max_size: Option<usize>,
+}
+
+// Routines calling themselves are used as loops, so the default is high
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1_000_000;
+// Reading the clock at every instruction would be too slow
+const INTERRUPTION_CHECK_INTERVAL: u64 = 1024;
+// Largest array built or grown by RANGE, RESIZE and FILL, 2 GiB. Without memory limit the
+// allocator could accept a bigger one that the system cannot actually provide, and the process
+// would be killed.
+const MAX_ARRAY_BYTES: usize = 1 << 31;
+
+// Stop an execution from another thread
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Bounds on what a program can use, they are shared by every instruction executed with them
+pub struct Limits {
+    // Number of instructions, the ones inside the called routines included
+    pub max_steps: Option<u64>,
+    // Number of routines being executed at the same time
+    pub max_call_depth: Option<usize>,
+    // Both are checked every few instructions, not after each one
+    pub cancellation: Option<CancellationToken>,
+    pub deadline: Option<Instant>,
+    steps: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_steps: None,
+            max_call_depth: Some(DEFAULT_MAX_CALL_DEPTH),
+            cancellation: None,
+            deadline: None,
+            steps: 0,
+        }
+    }
+}
+
+impl Limits {
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub(crate) fn count_step(&mut self) -> Result<(), VMError> {
+        if let Some(max_steps) = self.max_steps {
+            if self.steps >= max_steps {
+                return Err(VMError::StepLimitExceeded(max_steps));
+            }
+        }
+        if self.steps.is_multiple_of(INTERRUPTION_CHECK_INTERVAL) {
+            self.check_interruption()?;
+        }
+        self.steps += 1;
+        Ok(())
+    }
+
+    fn check_interruption(&self) -> Result<(), VMError> {
+        if let Some(cancellation) = &self.cancellation {
+            if cancellation.is_cancelled() {
+                return Err(VMError::Cancelled);
+            }
+        }
+        // The clock is only read if there is a deadline, there is no clock in the browser
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(VMError::Timeout),
+            _ => Ok(()),
+        }
+    }
 }
 
-#[derive(Error, Debug)]
-pub enum VMError {
-    #[error("Variable `{0}` does not exist")]
-    VariableDoesNotExist(String),
-    #[error("Routine `{0}` does not exist")]
-    RoutineDoesNotExist(String),
-    #[error("A boolean was expected but instead got `{0}`")]
-    ExpectedBoolean(Value),
-    #[error("Type are mismatching, got `{0}` and `{1}`")]
-    MismatchingTypes(Value, Value),
-    #[error("Expected an array but got {0}")]
-    ExpectedArray(Value),
-    #[error("Expected an arithmetic type but bot `{0}` and `{1}`")]
-    ExpectedArithmeticTypes(Value, Value),
-    #[error("Index, with value `{index:?}`, is out of bound. Array size is `{index:?}`")]
-    IndexOutOfBound { array_size: usize, index: usize },
-    #[error("Index is negative")]
-    NegativeIndex,
-    #[error("Index must be an integer")]
-    NonIntegerIndex,
-    #[error("Error while adding something to the output buffer")]
-    OutputBufferError(#[from] std::fmt::Error)
+// Identify a memory so the compiled routines are not used with the slots of another one
+static NEXT_MEMORY_ID: AtomicUsize = AtomicUsize::new(1);
+
+impl Default for Memory {
+    fn default() -> Memory {
+        Memory {
+            id: NEXT_MEMORY_ID.fetch_add(1, Ordering::Relaxed),
+            slots: HashMap::new(),
+            names: Vec::new(),
+            values: Vec::new(),
+            size: 0,
+            max_size: None,
+        }
+    }
 }
 
 impl Memory {
+    // The value is counted in the memory used but it is stored even if it goes over the limit
     pub fn store(&mut self, name: &str, value: Value) {
-        self.values.insert(String::from(name), value);
+        let slot = self.slot(name);
+        let old_size = self.values[slot].as_ref().map_or(0, value_size);
+        self.size = self.size.saturating_sub(old_size) + value_size(&value);
+        self.values[slot] = Some(value);
     }
 
     pub fn free(&mut self, name: &str) -> Result<(), VMError> {
-        match self.values.remove(name) {
+        match self.slots.get(name) {
+            Some(slot) => self.free_slot(*slot),
             None => Err(VMError::VariableDoesNotExist(String::from(name))),
-            _ => Ok(()),
         }
     }
 
     pub fn load(&self, name: &str) -> Result<&Value, VMError> {
-        match self.values.get(name) {
-            Some(value) => Ok(value),
+        match self.slots.get(name) {
+            Some(slot) => self.load_slot(*slot),
             None => Err(VMError::VariableDoesNotExist(String::from(name))),
         }
     }
 
     pub fn load_mut(&mut self, name: &str) -> Result<&mut Value, VMError> {
-        match self.values.get_mut(name) {
-            Some(value) => Ok(value),
+        match self.slots.get(name) {
+            Some(slot) => self.load_slot_mut(*slot),
             None => Err(VMError::VariableDoesNotExist(String::from(name))),
         }
     }
 
-    pub fn load_index(&self, name: &str) -> Result<usize, VMError> {
-        let index = self.load(name)?;
-        let index = match index {
-            Value::Integer(index) => index,
-            _ => return Err(VMError::NonIntegerIndex),
-        };
+    // Position in the array `array` of the element at the index stored in `name`, see element_index
+    pub fn load_index(&self, name: &str, array: &str) -> Result<usize, VMError> {
+        match (self.slots.get(name), self.slots.get(array)) {
+            (Some(index), Some(array)) => self.element_index(*array, *index),
+            (None, _) => Err(VMError::VariableDoesNotExist(String::from(name))),
+            (_, None) => Err(VMError::VariableDoesNotExist(String::from(array))),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Value)> {
+        self.names
+            .iter()
+            .zip(self.values.iter())
+            .filter_map(|(name, value)| value.as_ref().map(|value| (name, value)))
+    }
+
+    // Approximate, a string or an array copied in another variable is counted twice even if
+    // it is shared until one of them is modified
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // Only the instructions are limited, the values stored by the host with `store` are not
+    pub fn set_max_size(&mut self, max_size: Option<usize>) {
+        self.max_size = max_size;
+    }
 
-        if *index > 0 {
-            let index = *index as usize;
-            Ok(index)
-        } else {
-            Err(VMError::NegativeIndex)
+    pub(crate) fn id(&self) -> usize {
+        self.id
+    }
+
+    pub(crate) fn slot(&mut self, name: &str) -> Slot {
+        if let Some(slot) = self.slots.get(name) {
+            return *slot;
         }
+        let slot = self.values.len();
+        self.slots.insert(String::from(name), slot);
+        self.names.push(String::from(name));
+        self.values.push(None);
+        slot
+    }
+
+    fn store_slot(&mut self, slot: Slot, value: Value) -> Result<(), VMError> {
+        let old_size = self.slot_size(slot);
+        let new_size = value_size(&value);
+        if new_size != old_size {
+            self.check_size(old_size, new_size)?;
+            self.size = self.size.saturating_sub(old_size) + new_size;
+        }
+        self.values[slot] = Some(value);
+        Ok(())
+    }
+
+    fn clear_slot(&mut self, slot: Slot) {
+        self.size = self.size.saturating_sub(self.slot_size(slot));
+        self.values[slot] = None;
+    }
+
+    fn free_slot(&mut self, slot: Slot) -> Result<(), VMError> {
+        let size = self.slot_size(slot);
+        match self.values[slot].take() {
+            Some(_) => {
+                self.size = self.size.saturating_sub(size);
+                Ok(())
+            }
+            None => Err(self.does_not_exist(slot)),
+        }
+    }
+
+    fn slot_size(&self, slot: Slot) -> usize {
+        self.values[slot].as_ref().map_or(0, value_size)
+    }
+
+    // Check that replacing `removed` bytes by `added` bytes stays under the limit
+    fn check_size(&self, removed: usize, added: usize) -> Result<(), VMError> {
+        let needed = self.size.saturating_sub(removed).saturating_add(added);
+        match self.max_size {
+            Some(max_size) if added > removed && needed > max_size => {
+                Err(VMError::MemoryLimitExceeded { max_size, needed })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // For the values modified in place, the size is checked before and updated after.
+    // Saturating because the host can modify the values with `load_mut` without being counted.
+    fn resize(&mut self, removed: usize, added: usize) {
+        self.size = self.size.saturating_sub(removed) + added;
+    }
+
+    fn load_slot(&self, slot: Slot) -> Result<&Value, VMError> {
+        match &self.values[slot] {
+            Some(value) => Ok(value),
+            None => Err(self.does_not_exist(slot)),
+        }
+    }
+
+    fn load_slot_mut(&mut self, slot: Slot) -> Result<&mut Value, VMError> {
+        if self.values[slot].is_none() {
+            return Err(self.does_not_exist(slot));
+        }
+        Ok(self.values[slot].as_mut().unwrap())
+    }
+
+    // Not checked yet, it depends on the size of the array
+    fn load_index_slot(&self, slot: Slot) -> Result<i64, VMError> {
+        match self.load_slot(slot)? {
+            Value::Integer(index) => Ok(*index),
+            _ => Err(VMError::NonIntegerIndex(self.operand(slot))),
+        }
+    }
+
+    fn load_integer_slot(&self, slot: Slot) -> Result<i64, VMError> {
+        match self.load_slot(slot)? {
+            Value::Integer(integer) => Ok(*integer),
+            _ => Err(VMError::ExpectedInteger(self.operand(slot))),
+        }
+    }
+
+    // Zero-based, a negative index counts from the end: -1 is the last element
+    fn element_index(&self, array: Slot, index: Slot) -> Result<usize, VMError> {
+        let array_size = self.array_size(array)?;
+        match position(self.load_index_slot(index)?, array_size) {
+            Some(position) if position < array_size => Ok(position),
+            _ => Err(self.index_out_of_bound(array, array_size, index)),
+        }
+    }
+
+    // Same as element_index but the size of the array is valid too, to insert after the last element
+    fn insertion_index(&self, array: Slot, index: Slot) -> Result<usize, VMError> {
+        let array_size = self.array_size(array)?;
+        match position(self.load_index_slot(index)?, array_size) {
+            Some(position) if position <= array_size => Ok(position),
+            _ => Err(self.index_out_of_bound(array, array_size, index)),
+        }
+    }
+
+    fn array_size(&self, slot: Slot) -> Result<usize, VMError> {
+        match self.load_slot(slot)? {
+            Value::ArrayOfInteger(array) => Ok(array.len()),
+            Value::ArrayOfNumber(array) => Ok(array.len()),
+            Value::ArrayOfString(array) => Ok(array.len()),
+            Value::ArrayOfBoolean(array) => Ok(array.len()),
+            _ => Err(VMError::ExpectedArray(self.operand(slot))),
+        }
+    }
+
+    // Checked before modifying the array, `element` is None for a literal
+    fn check_element(
+        &self,
+        array: Slot,
+        value: &Value,
+        element: Option<Slot>,
+    ) -> Result<(), VMError> {
+        match (self.load_slot(array)?, value) {
+            (Value::ArrayOfInteger(_), Value::Integer(_))
+            | (Value::ArrayOfNumber(_), Value::Number(_))
+            | (Value::ArrayOfString(_), Value::String(_))
+            | (Value::ArrayOfBoolean(_), Value::Boolean(_)) => Ok(()),
+            (
+                Value::ArrayOfInteger(_)
+                | Value::ArrayOfNumber(_)
+                | Value::ArrayOfString(_)
+                | Value::ArrayOfBoolean(_),
+                _,
+            ) => Err(VMError::MismatchingTypes(
+                self.operand(array),
+                Operand::new(element.map(|slot| self.name_of(slot)), value),
+            )),
+            _ => Err(VMError::ExpectedArray(self.operand(array))),
+        }
+    }
+
+    fn index_out_of_bound(&self, array: Slot, array_size: usize, index: Slot) -> VMError {
+        VMError::IndexOutOfBound {
+            array: self.operand(array),
+            array_size,
+            index: self.operand(index),
+        }
+    }
+
+    // A variable in an error, it has been loaded by the failed instruction
+    fn operand(&self, slot: Slot) -> Operand {
+        let value = self.values[slot].as_ref().expect("Loaded before the error");
+        Operand::new(Some(self.name_of(slot)), value)
+    }
+
+    // The names are only needed to build the errors
+    fn does_not_exist(&self, slot: Slot) -> VMError {
+        VMError::VariableDoesNotExist(self.name_of(slot).to_string())
+    }
+
+    fn name_of(&self, slot: Slot) -> &str {
+        self.names[slot].as_str()
     }
 }
 
-pub type Routines = HashMap<String, Routine>;
+// Approximate size of a value: the value itself and what it owns on the heap
+fn value_size(value: &Value) -> usize {
+    let owned = match value {
+        Value::String(string) => string.len(),
+        Value::ArrayOfInteger(array) => array.len() * size_of::<i64>(),
+        Value::ArrayOfNumber(array) => array.len() * size_of::<f64>(),
+        Value::ArrayOfString(array) => array.iter().map(|s| string_element_size(s)).sum(),
+        Value::ArrayOfBoolean(array) => array.len(),
+        Value::Integer(_) | Value::Number(_) | Value::Char(_) | Value::Boolean(_) => 0,
+    };
+    size_of::<Value>() + owned
+}
+
+// Size of a value once it is an element of an array
+fn element_size(value: &Value) -> usize {
+    match value {
+        Value::Integer(_) => size_of::<i64>(),
+        Value::Number(_) => size_of::<f64>(),
+        Value::Boolean(_) => 1,
+        Value::String(string) => string_element_size(string),
+        _ => 0,
+    }
+}
+
+fn string_element_size(string: &str) -> usize {
+    size_of::<String>() + string.len()
+}
+
+// Bytes removed and added when an array goes from `size` to `new_size` elements
+fn resized_size(
+    size: usize,
+    new_size: usize,
+    element_size: usize,
+) -> Result<(usize, usize), VMError> {
+    if new_size < size {
+        Ok(((size - new_size) * element_size, 0))
+    } else {
+        Ok((0, array_bytes(new_size - size, element_size, new_size)?))
+    }
+}
+
+// Bytes of `count` elements, refused when they could never be allocated. `elements` is the size
+// of the whole array, the one reported.
+fn array_bytes(count: usize, element_size: usize, elements: usize) -> Result<usize, VMError> {
+    count
+        .checked_mul(element_size)
+        .ok_or(VMError::AllocationFailed(elements))
+}
+
+// Checked after the memory limit, which gives a more precise error
+fn check_allocation(bytes: usize, elements: usize) -> Result<(), VMError> {
+    match bytes > MAX_ARRAY_BYTES {
+        true => Err(VMError::AllocationFailed(elements)),
+        false => Ok(()),
+    }
+}
+
+// Reserved first so a failed allocation is reported instead of aborting the process
+fn grow<T: Clone>(array: &mut Vec<T>, size: usize, value: T) -> Result<(), VMError> {
+    if let Some(added) = size.checked_sub(array.len()) {
+        array
+            .try_reserve_exact(added)
+            .map_err(|_| VMError::AllocationFailed(size))?;
+    }
+    array.resize(size, value);
+    Ok(())
+}
 
 pub fn execute(
     memory: &mut Memory,
@@ -82,462 +434,1421 @@ pub fn execute(
     instruction: &Instruction,
     output: &mut String,
 ) -> Result<(), VMError> {
-    // Todo: refactor this shit enormous match
-    match instruction {
-        Instruction::Store(Store { dest, value }) => memory.store(dest, value.clone()),
-        Instruction::Copy(Copy { input, dest }) => {
-            let copied = memory.load(input.as_str())?.clone();
-            memory.store(dest, copied);
-        }
-        Instruction::Free(Free { dest }) => memory.free(dest.as_str())?,
-        Instruction::Print(Print { input }) => {
-            let value = memory.load(input.as_str())?;
-            write!(output, "{}", value)?;
+    execute_with_limits(
+        memory,
+        routines,
+        instruction,
+        output,
+        &mut Limits::default(),
+    )
+}
+
+pub fn execute_with_limits(
+    memory: &mut Memory,
+    routines: &Routines,
+    instruction: &Instruction,
+    output: &mut String,
+    limits: &mut Limits,
+) -> Result<(), VMError> {
+    Execution::new(instruction, 0, memory, routines).run(memory, routines, output, limits)
+}
+
+// Same as `execute_with_limits` but slower, the observer is notified around every instruction
+pub fn execute_observed(
+    memory: &mut Memory,
+    routines: &Routines,
+    instruction: &Instruction,
+    line: usize,
+    output: &mut String,
+    limits: &mut Limits,
+    observer: &mut dyn Observer,
+) -> Result<(), VMError> {
+    Execution::new(instruction, line, memory, routines).run_observed(
+        instruction,
+        memory,
+        routines,
+        output,
+        limits,
+        observer,
+    )
+}
+
+// Execute one operation, returns the routine to call if there is one
+pub(crate) fn execute_op(
+    op: &Op,
+    memory: &mut Memory,
+    output: &mut String,
+) -> Result<Option<RoutineCall>, VMError> {
+    match op {
+        Op::Store { dest, value } => memory.store_slot(*dest, value.clone())?,
+        Op::Copy { input, dest } => {
+            let copied = memory.load_slot(*input)?.clone();
+            memory.store_slot(*dest, copied)?;
         }
-        Instruction::Call(Call { routine_name }) => {
-            call_routine(memory, routines, routine_name.as_str(), output)?;
+        Op::Free { dest } => memory.free_slot(*dest)?,
+        Op::Print { input } => {
+            let value = memory.load_slot(*input)?;
+            write!(output, "{}", value)?;
         }
-        Instruction::CallIf(CallIf {
-            routine_name,
-            boolean_input_name,
-        }) => {
-            let value = memory.load(boolean_input_name.as_str())?;
-            let call = match value {
+        Op::Call { routine } => return Ok(Some(RoutineCall::new(resolve(routine)?))),
+        Op::CallIf { routine, condition } => {
+            let call = match memory.load_slot(*condition)? {
                 Value::Boolean(b) => *b,
-                _ => return Err(VMError::ExpectedBoolean(value.clone())),
+                _ => return Err(VMError::ExpectedBoolean(memory.operand(*condition))),
             };
 
             if call {
-                call_routine(memory, routines, routine_name.as_str(), output)?;
+                return Ok(Some(RoutineCall::new(resolve(routine)?)));
             }
         }
-        Instruction::Add(Add {
-            input_a,
-            input_b,
-            dest,
-        }) => {
-            let a = memory.load(input_a.as_str())?;
-            let b = memory.load(input_b.as_str())?;
-            let result = match (a, b) {
-                (Value::Integer(a), Value::Integer(b)) => Value::Integer(a + b),
-                (Value::Number(a), Value::Number(b)) => Value::Number(a + b),
-                _ => return Err(VMError::ExpectedArithmeticTypes(a.clone(), b.clone())),
-            };
-            memory.store(dest.as_str(), result);
-        }
-        Instruction::Substract(Substract {
-            input_a,
-            input_b,
-            dest,
-        }) => {
-            let a = memory.load(input_a.as_str())?;
-            let b = memory.load(input_b.as_str())?;
-            let result = match (a, b) {
-                (Value::Integer(a), Value::Integer(b)) => Value::Integer(a - b),
-                (Value::Number(a), Value::Number(b)) => Value::Number(a - b),
-                _ => return Err(VMError::ExpectedArithmeticTypes(a.clone(), b.clone())),
-            };
-            memory.store(dest.as_str(), result);
-        }
-        Instruction::Multiply(Multiply {
-            input_a,
-            input_b,
+        Op::Arithmetic {
+            operator,
+            a,
+            b,
             dest,
-        }) => {
-            let a = memory.load(input_a.as_str())?;
-            let b = memory.load(input_b.as_str())?;
-            let result = match (a, b) {
-                (Value::Integer(a), Value::Integer(b)) => Value::Integer(a * b),
-                (Value::Number(a), Value::Number(b)) => Value::Number(a * b),
-                _ => return Err(VMError::ExpectedArithmeticTypes(a.clone(), b.clone())),
+        } => {
+            let value_a = memory.load_slot(*a)?;
+            let value_b = memory.load_slot(*b)?;
+            let result = match arithmetic(*operator, value_a, value_b) {
+                Ok(result) => result,
+                Err(error) => return Err(arithmetic_error(error, memory, *a, *b)),
             };
-            memory.store(dest.as_str(), result);
+            memory.store_slot(*dest, result)?;
         }
-        Instruction::Divide(Divide {
-            input_a,
-            input_b,
+        Op::Logical {
+            operator,
+            a,
+            b,
             dest,
-        }) => {
-            let a = memory.load(input_a.as_str())?;
-            let b = memory.load(input_b.as_str())?;
-            let result = match (a, b) {
-                (Value::Integer(a), Value::Integer(b)) => Value::Integer(a / b),
-                (Value::Number(a), Value::Number(b)) => Value::Number(a / b),
-                _ => return Err(VMError::ExpectedArithmeticTypes(a.clone(), b.clone())),
-            };
-            memory.store(dest.as_str(), result);
-        }
-        Instruction::Modulo(Modulo {
-            input_a,
-            input_b,
-            dest,
-        }) => {
-            let a = memory.load(input_a.as_str())?;
-            let b = memory.load(input_b.as_str())?;
-            let result = match (a, b) {
-                (Value::Integer(a), Value::Integer(b)) => Value::Integer(a % b),
-                _ => return Err(VMError::ExpectedArithmeticTypes(a.clone(), b.clone())),
-            };
-            memory.store(dest.as_str(), result);
-        }
-        Instruction::LogicalAnd(LogicalAnd {
-            input_a,
-            input_b,
-            dest,
-        }) => {
-            let a = memory.load(input_a.as_str())?;
-            let b = memory.load(input_b.as_str())?;
-            let result = match (a, b) {
-                (Value::Boolean(a), Value::Boolean(b)) => Value::Boolean(*a && *b),
-                _ => return Err(VMError::MismatchingTypes(a.clone(), b.clone())),
-            };
-            memory.store(dest.as_str(), result);
-        }
-        Instruction::LogicalOr(LogicalOr {
-            input_a,
-            input_b,
-            dest,
-        }) => {
-            let a = memory.load(input_a.as_str())?;
-            let b = memory.load(input_b.as_str())?;
-            let result = match (a, b) {
-                (Value::Boolean(a), Value::Boolean(b)) => Value::Boolean(*a || *b),
-                _ => return Err(VMError::MismatchingTypes(a.clone(), b.clone())),
+        } => {
+            let value_a = memory.load_slot(*a)?;
+            let value_b = memory.load_slot(*b)?;
+            let result = match (operator, value_a, value_b) {
+                (Logical::And, Value::Boolean(a), Value::Boolean(b)) => Value::Boolean(*a && *b),
+                (Logical::Or, Value::Boolean(a), Value::Boolean(b)) => Value::Boolean(*a || *b),
+                (_, Value::Boolean(_), _) => {
+                    return Err(VMError::ExpectedBoolean(memory.operand(*b)))
+                }
+                _ => return Err(VMError::ExpectedBoolean(memory.operand(*a))),
             };
-            memory.store(dest.as_str(), result);
+            memory.store_slot(*dest, result)?;
         }
-        Instruction::LogicalNot(LogicalNot { input, dest }) => {
-            let a = memory.load(input.as_str())?;
-            let result = match a {
+        Op::Not { input, dest } => {
+            let result = match memory.load_slot(*input)? {
                 Value::Boolean(a) => Value::Boolean(!*a),
-                _ => return Err(VMError::ExpectedBoolean(a.clone())),
+                _ => return Err(VMError::ExpectedBoolean(memory.operand(*input))),
             };
-            memory.store(dest.as_str(), result);
-        }
-        Instruction::CompareEqual(CompareEqual {
-            input_a,
-            input_b,
-            dest,
-        }) => {
-            let a = memory.load(input_a.as_str())?;
-            let b = memory.load(input_b.as_str())?;
-            if std::mem::discriminant(a) != std::mem::discriminant(b) {
-                return Err(VMError::MismatchingTypes(a.clone(), b.clone()));
-            }
-            let result = Value::Boolean(a == b);
-            memory.store(dest.as_str(), result);
-        }
-        Instruction::CompareDifferent(CompareDifferent {
-            input_a,
-            input_b,
-            dest,
-        }) => {
-            let a = memory.load(input_a.as_str())?;
-            let b = memory.load(input_b.as_str())?;
-            if std::mem::discriminant(a) != std::mem::discriminant(b) {
-                return Err(VMError::MismatchingTypes(a.clone(), b.clone()));
-            }
-            let result = Value::Boolean(a != b);
-            memory.store(dest.as_str(), result);
-        }
-        Instruction::CompareLess(CompareLess {
-            input_a,
-            input_b,
-            dest,
-        }) => {
-            let a = memory.load(input_a.as_str())?;
-            let b = memory.load(input_b.as_str())?;
-            if std::mem::discriminant(a) != std::mem::discriminant(b) {
-                return Err(VMError::MismatchingTypes(a.clone(), b.clone()));
-            }
-            let result = Value::Boolean(a < b);
-            memory.store(dest.as_str(), result);
-        }
-        Instruction::CompareLessOrEqual(CompareLessOrEqual {
-            input_a,
-            input_b,
-            dest,
-        }) => {
-            let a = memory.load(input_a.as_str())?;
-            let b = memory.load(input_b.as_str())?;
-            if std::mem::discriminant(a) != std::mem::discriminant(b) {
-                return Err(VMError::MismatchingTypes(a.clone(), b.clone()));
-            }
-            let result = Value::Boolean(a <= b);
-            memory.store(dest.as_str(), result);
+            memory.store_slot(*dest, result)?;
         }
-        Instruction::CompareGreater(CompareGreater {
-            input_a,
-            input_b,
+        Op::Compare {
+            comparison,
+            a,
+            b,
             dest,
-        }) => {
-            let a = memory.load(input_a.as_str())?;
-            let b = memory.load(input_b.as_str())?;
+        } => {
+            let (slot_a, slot_b) = (*a, *b);
+            let a = memory.load_slot(slot_a)?;
+            let b = memory.load_slot(slot_b)?;
             if std::mem::discriminant(a) != std::mem::discriminant(b) {
-                return Err(VMError::MismatchingTypes(a.clone(), b.clone()));
+                return Err(VMError::MismatchingTypes(
+                    memory.operand(slot_a),
+                    memory.operand(slot_b),
+                ));
             }
-            let result = Value::Boolean(a > b);
-            memory.store(dest.as_str(), result);
-        }
-        Instruction::CompareGreaterOrEqual(CompareGreaterOrEqual {
-            input_a,
-            input_b,
-            dest,
-        }) => {
-            let a = memory.load(input_a.as_str())?;
-            let b = memory.load(input_b.as_str())?;
-            if std::mem::discriminant(a) != std::mem::discriminant(b) {
-                return Err(VMError::MismatchingTypes(a.clone(), b.clone()));
-            }
-            let result = Value::Boolean(a >= b);
-            memory.store(dest.as_str(), result);
+            let result = match comparison {
+                Comparison::Equal => a == b,
+                Comparison::Different => a != b,
+                Comparison::Less => a < b,
+                Comparison::LessOrEqual => a <= b,
+                Comparison::Greater => a > b,
+                Comparison::GreaterOrEqual => a >= b,
+            };
+            memory.store_slot(*dest, Value::Boolean(result))?;
         }
-        Instruction::GetAt(GetAt {
-            array_input,
-            index,
-            output,
-        }) => {
-            let array_input = memory.load(array_input.as_str())?;
-            let index = memory.load_index(index.as_str())?;
-
-            let result = match array_input {
-                Value::ArrayOfInteger(array_input) => {
-                    Value::Integer(vector_get_copy_at(array_input, index)?)
-                }
-                Value::ArrayOfNumber(array_input) => {
-                    Value::Number(vector_get_copy_at(array_input, index)?)
-                }
-                Value::ArrayOfString(array_input) => {
-                    Value::String(vector_get_copy_at(array_input, index)?)
-                }
-                Value::ArrayOfBoolean(array_input) => {
-                    Value::Boolean(vector_get_copy_at(array_input, index)?)
-                }
-                _ => return Err(VMError::ExpectedArray(array_input.clone())),
+        Op::GetAt { array, index, dest } => {
+            let index = memory.element_index(*array, *index)?;
+            let result = match memory.load_slot(*array)? {
+                Value::ArrayOfInteger(array) => Value::Integer(array[index]),
+                Value::ArrayOfNumber(array) => Value::Number(array[index]),
+                Value::ArrayOfString(array) => Value::from(array[index].clone()),
+                Value::ArrayOfBoolean(array) => Value::Boolean(array[index]),
+                _ => unreachable!("Checked by element_index"),
             };
-            memory.store(output.as_str(), result);
+            memory.store_slot(*dest, result)?;
         }
-        Instruction::StoreAt(StoreAt {
-            array_output,
+        Op::StoreAt {
+            array,
             index,
             value,
-        }) => {
-            store_at(memory, array_output, index, value.clone())?;
+        } => {
+            store_at(memory, *array, *index, value.clone(), None)?;
         }
-        // Todo: put the thing above in a function, then below load the input then use the function store at
-        Instruction::CopyAt(CopyAt {
-            array_output,
+        Op::CopyAt {
+            array,
             index,
             input,
-        }) => {
-            let input = memory.load(input.as_str())?.clone();
-            store_at(memory, array_output, index, input)?;
-        }
-        Instruction::Size(Size {
-            array_input,
-            output,
-        }) => {
-            let array = memory.load(array_input.as_str())?;
-            let size = match array {
-                Value::ArrayOfInteger(array) => array.len(),
-                Value::ArrayOfNumber(array) => array.len(),
-                Value::ArrayOfString(array) => array.len(),
-                Value::ArrayOfBoolean(array) => array.len(),
-                _ => return Err(VMError::ExpectedArray(array.clone())),
+        } => {
+            let value = memory.load_slot(*input)?.clone();
+            store_at(memory, *array, *index, value, Some(*input))?;
+        }
+        Op::Size { array, dest } => {
+            let size = memory.array_size(*array)?;
+            memory.store_slot(*dest, Value::Integer(size as i64))?;
+        }
+        Op::Resize { array, size } => {
+            // A negative size removes elements from the end: -1 removes the last one
+            let array_size = memory.array_size(*array)?;
+            let size = match position(memory.load_index_slot(*size)?, array_size) {
+                Some(position) => position,
+                None => return Err(memory.index_out_of_bound(*array, array_size, *size)),
             };
-            memory.store(output.as_str(), Value::Integer(size as i64));
-        }
-        Instruction::Resize(Resize {
-            array_input,
-            new_size,
-        }) => {
-            let size = memory.load_index(new_size.as_str())?;
-            let array = memory.load_mut(array_input.as_str())?;
-            match array {
-                Value::ArrayOfInteger(array) => array.resize(size, 0),
-                Value::ArrayOfNumber(array) => array.resize(size, 0.0),
-                Value::ArrayOfString(array) => array.resize(size, String::new()),
-                Value::ArrayOfBoolean(array) => array.resize(size, false),
-                _ => return Err(VMError::ExpectedArray(array.clone())),
+            let (removed, added) = match memory.load_slot(*array)? {
+                Value::ArrayOfInteger(array) => resized_size(array.len(), size, size_of::<i64>())?,
+                Value::ArrayOfNumber(array) => resized_size(array.len(), size, size_of::<f64>())?,
+                Value::ArrayOfBoolean(array) => resized_size(array.len(), size, 1)?,
+                Value::ArrayOfString(array) if size < array.len() => (
+                    array[size..].iter().map(|s| string_element_size(s)).sum(),
+                    0,
+                ),
+                Value::ArrayOfString(array) => {
+                    resized_size(array.len(), size, size_of::<String>())?
+                }
+                _ => unreachable!("Checked by array_size"),
+            };
+            memory.check_size(removed, added)?;
+            check_allocation(added, size)?;
+            match memory.load_slot_mut(*array)? {
+                Value::ArrayOfInteger(array) => grow(Rc::make_mut(array), size, 0)?,
+                Value::ArrayOfNumber(array) => grow(Rc::make_mut(array), size, 0.0)?,
+                Value::ArrayOfString(array) => grow(Rc::make_mut(array), size, String::new())?,
+                Value::ArrayOfBoolean(array) => grow(Rc::make_mut(array), size, false)?,
+                _ => unreachable!("Checked by array_size"),
             };
+            memory.resize(removed, added);
         }
-        Instruction::Insert(Insert {
-            array_output,
+        Op::Insert {
+            array,
             index,
             input,
-        }) => {
-            insert(memory, array_output, index, input)?;
+        } => {
+            insert(memory, *array, *index, *input)?;
         }
-        Instruction::PushBack(PushBack {
-            array_output,
-            input,
-        }) => {
-            let value = memory.load(input.as_str())?.clone();
-            let array_output = memory.load_mut(array_output.as_str())?;
+        Op::PushBack { array, input } => {
+            let value = memory.load_slot(*input)?.clone();
+            memory.check_element(*array, &value, Some(*input))?;
+            let added = element_size(&value);
+            memory.check_size(0, added)?;
+            let array_output = memory.load_slot_mut(*array)?;
             match (array_output, value) {
                 (Value::ArrayOfInteger(array_output), Value::Integer(value)) => {
-                    array_output.push(value);
+                    Rc::make_mut(array_output).push(value);
                 }
                 (Value::ArrayOfNumber(array_output), Value::Number(value)) => {
-                    array_output.push(value);
+                    Rc::make_mut(array_output).push(value);
                 }
                 (Value::ArrayOfString(array_output), Value::String(value)) => {
-                    array_output.push(value);
+                    Rc::make_mut(array_output).push(Rc::unwrap_or_clone(value));
                 }
                 (Value::ArrayOfBoolean(array_output), Value::Boolean(value)) => {
-                    array_output.push(value);
+                    Rc::make_mut(array_output).push(value);
                 }
-                (a, b) => return Err(VMError::MismatchingTypes(a.clone(), b)),
+                _ => unreachable!("Checked by check_element"),
             };
+            memory.resize(0, added);
         }
-        Instruction::Concat(Concat {
-            input_a,
-            input_b,
-            dest,
-        }) => {
-            let input_a = memory.load(input_a.as_str())?;
-            let input_b = memory.load(input_b.as_str())?;
-            let result = match (input_a, input_b) {
-                (Value::String(input_a), Value::String(input_b)) => {
-                    Value::String(format!("{}{}", input_a, input_b))
+        Op::Concat { a, b, dest } => {
+            let input_a = memory.load_slot(*a)?;
+            let input_b = memory.load_slot(*b)?;
+            if !matches!(input_a, Value::String(_)) && !is_array(input_a) {
+                return Err(VMError::ExpectedConcatenable(memory.operand(*a)));
+            }
+            if std::mem::discriminant(input_a) != std::mem::discriminant(input_b) {
+                return Err(VMError::MismatchingTypes(
+                    memory.operand(*a),
+                    memory.operand(*b),
+                ));
+            }
+            // Only the Rc are cloned, the content is copied when it is extended
+            let (mut result, suffix) = (input_a.clone(), input_b.clone());
+            // Checked before building the result, it could be huge
+            let added = value_size(&result) + value_size(&suffix) - size_of::<Value>();
+            memory.check_size(memory.slot_size(*dest), added)?;
+            // When appending to the same variable its content is not shared anymore so it is extended in place
+            if a == dest {
+                memory.clear_slot(*dest);
+            }
+            match (&mut result, suffix) {
+                (Value::String(result), Value::String(suffix)) => {
+                    Rc::make_mut(result).push_str(suffix.as_str())
+                }
+                (Value::ArrayOfInteger(result), Value::ArrayOfInteger(suffix)) => {
+                    Rc::make_mut(result).extend_from_slice(&suffix)
+                }
+                (Value::ArrayOfNumber(result), Value::ArrayOfNumber(suffix)) => {
+                    Rc::make_mut(result).extend_from_slice(&suffix)
                 }
-                // todo concat arrays
-                _ => return Err(VMError::MismatchingTypes(input_a.clone(), input_b.clone())),
+                (Value::ArrayOfString(result), Value::ArrayOfString(suffix)) => {
+                    Rc::make_mut(result).extend_from_slice(&suffix)
+                }
+                (Value::ArrayOfBoolean(result), Value::ArrayOfBoolean(suffix)) => {
+                    Rc::make_mut(result).extend_from_slice(&suffix)
+                }
+                _ => unreachable!("Same types, a STRING or an array"),
+            }
+            memory.store_slot(*dest, result)?;
+        }
+        Op::Erase { array, index } => {
+            let index = memory.element_index(*array, *index)?;
+            let removed = match memory.load_slot_mut(*array)? {
+                Value::ArrayOfInteger(array) => {
+                    Rc::make_mut(array).remove(index);
+                    size_of::<i64>()
+                }
+                Value::ArrayOfNumber(array) => {
+                    Rc::make_mut(array).remove(index);
+                    size_of::<f64>()
+                }
+                Value::ArrayOfString(array) => {
+                    string_element_size(&Rc::make_mut(array).remove(index))
+                }
+                Value::ArrayOfBoolean(array) => {
+                    Rc::make_mut(array).remove(index);
+                    1
+                }
+                _ => unreachable!("Checked by element_index"),
             };
-            memory.store(dest.as_str(), result);
-        }
-        Instruction::Erase(Erase { array_input, index }) => {
-            let index = memory.load_index(index.as_str())?;
-            let array = memory.load_mut(array_input.as_str())?;
-            match array {
-                Value::ArrayOfInteger(array) => vector_remove(array, index)?,
-                Value::ArrayOfNumber(array) => vector_remove(array, index)?,
-                Value::ArrayOfString(array) => vector_remove(array, index)?,
-                Value::ArrayOfBoolean(array) => vector_remove(array, index)?,
-                _ => return Err(VMError::ExpectedArray(array.clone())),
+            memory.resize(removed, 0);
+        }
+        Op::Sort { array } => {
+            memory.array_size(*array)?;
+            match memory.load_slot_mut(*array)? {
+                Value::ArrayOfInteger(array) => Rc::make_mut(array).sort(),
+                Value::ArrayOfNumber(array) => Rc::make_mut(array).sort_by(compare_numbers),
+                Value::ArrayOfString(array) => Rc::make_mut(array).sort(),
+                Value::ArrayOfBoolean(array) => Rc::make_mut(array).sort(),
+                _ => unreachable!("Checked by array_size"),
+            }
+        }
+        Op::Reverse { array } => {
+            memory.array_size(*array)?;
+            match memory.load_slot_mut(*array)? {
+                Value::ArrayOfInteger(array) => Rc::make_mut(array).reverse(),
+                Value::ArrayOfNumber(array) => Rc::make_mut(array).reverse(),
+                Value::ArrayOfString(array) => Rc::make_mut(array).reverse(),
+                Value::ArrayOfBoolean(array) => Rc::make_mut(array).reverse(),
+                _ => unreachable!("Checked by array_size"),
+            }
+        }
+        Op::Fill { array, input } => {
+            let value = memory.load_slot(*input)?.clone();
+            memory.check_element(*array, &value, Some(*input))?;
+            // Only the strings change the size of the array
+            let (removed, added, elements) = match (memory.load_slot(*array)?, &value) {
+                (Value::ArrayOfString(array), Value::String(value)) => (
+                    array.iter().map(|s| string_element_size(s)).sum(),
+                    array_bytes(array.len(), string_element_size(value), array.len())?,
+                    array.len(),
+                ),
+                _ => (0, 0, 0),
             };
+            memory.check_size(removed, added)?;
+            check_allocation(added, elements)?;
+            match (memory.load_slot_mut(*array)?, value) {
+                (Value::ArrayOfInteger(array), Value::Integer(value)) => {
+                    Rc::make_mut(array).fill(value)
+                }
+                (Value::ArrayOfNumber(array), Value::Number(value)) => {
+                    Rc::make_mut(array).fill(value)
+                }
+                (Value::ArrayOfString(array), Value::String(value)) => {
+                    Rc::make_mut(array).fill(Rc::unwrap_or_clone(value))
+                }
+                (Value::ArrayOfBoolean(array), Value::Boolean(value)) => {
+                    Rc::make_mut(array).fill(value)
+                }
+                _ => unreachable!("Checked by check_element"),
+            }
+            memory.resize(removed, added);
+        }
+        Op::IndexOf { array, input, dest } => {
+            let index = match find(memory, *array, *input)? {
+                Some(index) => index as i64,
+                None => -1,
+            };
+            memory.store_slot(*dest, Value::Integer(index))?;
+        }
+        Op::Contains { array, input, dest } => {
+            let found = find(memory, *array, *input)?.is_some();
+            memory.store_slot(*dest, Value::Boolean(found))?;
+        }
+        Op::Slice {
+            array,
+            start,
+            end,
+            dest,
+        } => {
+            // Both ends can be the size of the array, an empty slice if end is before start
+            let start = memory.insertion_index(*array, *start)?;
+            let end = memory.insertion_index(*array, *end)?.max(start);
+            let result = match memory.load_slot(*array)? {
+                Value::ArrayOfInteger(array) => Value::from(array[start..end].to_vec()),
+                Value::ArrayOfNumber(array) => Value::from(array[start..end].to_vec()),
+                Value::ArrayOfString(array) => Value::from(array[start..end].to_vec()),
+                Value::ArrayOfBoolean(array) => Value::from(array[start..end].to_vec()),
+                _ => unreachable!("Checked by insertion_index"),
+            };
+            memory.store_slot(*dest, result)?;
+        }
+        Op::Range { start, end, dest } => {
+            let start = memory.load_integer_slot(*start)?;
+            let end = memory.load_integer_slot(*end)?;
+            // Checked before building the array, it could be huge
+            let size = usize::try_from(end.saturating_sub(start)).unwrap_or(0);
+            let added = array_bytes(size, size_of::<i64>(), size)?;
+            memory.check_size(
+                memory.slot_size(*dest),
+                size_of::<Value>().saturating_add(added),
+            )?;
+            check_allocation(added, size)?;
+            let mut range = Vec::new();
+            range
+                .try_reserve_exact(size)
+                .map_err(|_| VMError::AllocationFailed(size))?;
+            range.extend(start..end);
+            memory.store_slot(*dest, Value::from(range))?;
+        }
+        Op::Assert { input } => match memory.load_slot(*input)? {
+            Value::Boolean(true) => (),
+            Value::Boolean(false) => {
+                let message = format!("`{}` is false", memory.name_of(*input));
+                return Err(VMError::AssertionFailed(message));
+            }
+            _ => return Err(VMError::ExpectedBoolean(memory.operand(*input))),
+        },
+        Op::AssertEqual { a, b } => {
+            let value_a = memory.load_slot(*a)?;
+            let value_b = memory.load_slot(*b)?;
+            if value_a != value_b {
+                let message = format!("{} but {}", memory.operand(*a), memory.operand(*b));
+                return Err(VMError::AssertionFailed(message));
+            }
+        }
+        Op::AssertError { routine } => {
+            return Ok(Some(RoutineCall {
+                routine: resolve(routine)?,
+                expects_error: true,
+            }))
+        }
+        Op::TypeOf { input, dest } => {
+            let type_name = memory.load_slot(*input)?.type_name();
+            memory.store_slot(*dest, Value::from(type_name))?;
+        }
+        Op::IsDefined { variable, dest } => {
+            let is_defined = memory.values[*variable].is_some();
+            memory.store_slot(*dest, Value::Boolean(is_defined))?;
+        }
+        Op::IsArray { input, dest } => {
+            let is_array = is_array(memory.load_slot(*input)?);
+            memory.store_slot(*dest, Value::Boolean(is_array))?;
         }
     };
-    Ok(())
+    Ok(None)
 }
 
-fn call_routine(
-    memory: &mut Memory,
-    routines: &Routines,
-    routine_name: &str,
-    output: &mut String,
-) -> Result<(), VMError> {
-    let routine = routines.get(routine_name);
-    let routine = match routine {
-        None => return Err(VMError::RoutineDoesNotExist(String::from(routine_name))),
-        Some(routine) => routine,
-    };
-
-    for i in routine.instructions.iter() {
-        execute(memory, routines, i, output)?;
+fn resolve(target: &Target) -> Result<usize, VMError> {
+    match target {
+        Target::Index(index) => Ok(*index),
+        Target::Unknown(name) => Err(VMError::RoutineDoesNotExist(name.clone())),
     }
-    Ok(())
 }
 
-fn vector_get_copy_at<T>(vec: &Vec<T>, index: usize) -> Result<T, VMError>
-where
-    T: Clone,
-{
-    match vec.get(index) {
-        Some(value) => Ok(value.clone()),
-        None => Err(VMError::IndexOutOfBound {
-            array_size: vec.len(),
-            index,
-        }),
-    }
+// Errors of arithmetic, the operands are only looked up when building the VMError
+enum ArithmeticError {
+    Types,
+    DivisionByZero,
+    Overflow(&'static str),
 }
 
-fn vector_set_at<T>(vec: &mut Vec<T>, new_value: T, index: usize) -> Result<(), VMError> {
-    match vec.get_mut(index) {
-        Some(old_value) => {
-            *old_value = new_value;
-            Ok(())
+// Inlined so the result does not go through a `Result` in the hot loop
+#[inline(always)]
+fn arithmetic(operator: Arithmetic, a: &Value, b: &Value) -> Result<Value, ArithmeticError> {
+    let result = match (operator, a, b) {
+        (Arithmetic::Divide | Arithmetic::Modulo, Value::Integer(_), Value::Integer(0)) => {
+            return Err(ArithmeticError::DivisionByZero)
         }
-        None => Err(VMError::IndexOutOfBound {
-            array_size: vec.len(),
-            index,
-        }),
-    }
+        (_, Value::Integer(a), Value::Integer(b)) => {
+            let (result, name) = match operator {
+                Arithmetic::Add => (a.checked_add(*b), "ADD"),
+                Arithmetic::Substract => (a.checked_sub(*b), "SUBSTRACT"),
+                Arithmetic::Multiply => (a.checked_mul(*b), "MULTIPLY"),
+                Arithmetic::Divide => (a.checked_div(*b), "DIVIDE"),
+                Arithmetic::Modulo => (a.checked_rem(*b), "MODULO"),
+            };
+            Value::Integer(result.ok_or(ArithmeticError::Overflow(name))?)
+        }
+        (Arithmetic::Add, Value::Number(a), Value::Number(b)) => Value::Number(a + b),
+        (Arithmetic::Substract, Value::Number(a), Value::Number(b)) => Value::Number(a - b),
+        (Arithmetic::Multiply, Value::Number(a), Value::Number(b)) => Value::Number(a * b),
+        (Arithmetic::Divide, Value::Number(a), Value::Number(b)) => Value::Number(a / b),
+        _ => return Err(ArithmeticError::Types),
+    };
+    Ok(result)
 }
 
-fn vector_insert<T>(vec: &mut Vec<T>, new_value: T, index: usize) -> Result<(), VMError> {
-    if vec.len() > index {
-        vec.insert(index, new_value);
-        Ok(())
-    } else {
-        Err(VMError::IndexOutOfBound {
-            array_size: vec.len(),
-            index,
-        })
+fn arithmetic_error(error: ArithmeticError, memory: &Memory, a: Slot, b: Slot) -> VMError {
+    match error {
+        ArithmeticError::Types => {
+            VMError::ExpectedArithmeticTypes(memory.operand(a), memory.operand(b))
+        }
+        ArithmeticError::DivisionByZero => VMError::DivisionByZero(memory.operand(b)),
+        ArithmeticError::Overflow(instruction) => VMError::IntegerOverflow {
+            instruction,
+            a: memory.operand(a),
+            b: memory.operand(b),
+        },
     }
 }
 
-fn vector_remove<T>(vec: &mut Vec<T>, index: usize) -> Result<(), VMError> {
-    if vec.len() > index {
-        vec.remove(index);
-        Ok(())
-    } else {
-        Err(VMError::IndexOutOfBound {
-            array_size: vec.len(),
-            index,
-        })
+fn is_array(value: &Value) -> bool {
+    matches!(
+        value,
+        Value::ArrayOfInteger(_)
+            | Value::ArrayOfNumber(_)
+            | Value::ArrayOfString(_)
+            | Value::ArrayOfBoolean(_)
+    )
+}
+
+// Total order for SORT, the NaN are equal to each other and greater than any other number
+fn compare_numbers(a: &f64, b: &f64) -> std::cmp::Ordering {
+    a.partial_cmp(b)
+        .unwrap_or_else(|| a.is_nan().cmp(&b.is_nan()))
+}
+
+// Position of the first element equal to the input
+fn find(memory: &Memory, array: Slot, input: Slot) -> Result<Option<usize>, VMError> {
+    let value = memory.load_slot(input)?;
+    memory.check_element(array, value, Some(input))?;
+    let position = match (memory.load_slot(array)?, value) {
+        (Value::ArrayOfInteger(array), Value::Integer(value)) => {
+            array.iter().position(|element| element == value)
+        }
+        (Value::ArrayOfNumber(array), Value::Number(value)) => {
+            array.iter().position(|element| element == value)
+        }
+        (Value::ArrayOfString(array), Value::String(value)) => array
+            .iter()
+            .position(|element| element.as_str() == value.as_str()),
+        (Value::ArrayOfBoolean(array), Value::Boolean(value)) => {
+            array.iter().position(|element| element == value)
+        }
+        _ => unreachable!("Checked by check_element"),
+    };
+    Ok(position)
+}
+
+// None if the index is negative and goes before the start of the array
+fn position(index: i64, array_size: usize) -> Option<usize> {
+    match usize::try_from(index) {
+        Ok(position) => Some(position),
+        Err(_) => usize::try_from(index.unsigned_abs())
+            .ok()
+            .and_then(|from_end| array_size.checked_sub(from_end)),
     }
 }
 
+// `element` is the variable the value comes from, None for a literal
 fn store_at(
     memory: &mut Memory,
-    array_output: &str,
-    index: &str,
+    array: Slot,
+    index: Slot,
     value: Value,
+    element: Option<Slot>,
 ) -> Result<(), VMError> {
-    let index = memory.load_index(index)?;
-    let array_output = memory.load_mut(array_output)?;
-    match (array_output, value) {
+    let index = memory.element_index(array, index)?;
+    memory.check_element(array, &value, element)?;
+    let added = element_size(&value);
+    memory.check_size(0, added)?;
+    let array_output = memory.load_slot_mut(array)?;
+    let removed = match (array_output, value) {
         (Value::ArrayOfInteger(array_output), Value::Integer(value)) => {
-            vector_set_at(array_output, value, index)?;
+            Rc::make_mut(array_output)[index] = value;
+            added
         }
         (Value::ArrayOfNumber(array_output), Value::Number(value)) => {
-            vector_set_at(array_output, value, index)?;
+            Rc::make_mut(array_output)[index] = value;
+            added
         }
         (Value::ArrayOfString(array_output), Value::String(value)) => {
-            vector_set_at(array_output, value, index)?;
+            let old_value = std::mem::replace(
+                &mut Rc::make_mut(array_output)[index],
+                Rc::unwrap_or_clone(value),
+            );
+            string_element_size(&old_value)
         }
         (Value::ArrayOfBoolean(array_output), Value::Boolean(value)) => {
-            vector_set_at(array_output, value, index)?;
+            Rc::make_mut(array_output)[index] = value;
+            added
         }
-        _ => return Err(VMError::MismatchingTypes(Value::Boolean(true), Value::Boolean(true))),
+        _ => unreachable!("Checked by check_element"),
     };
+    memory.resize(removed, added);
     Ok(())
 }
 
-fn insert(
-    memory: &mut Memory,
-    array_output: &str,
-    index: &str,
-    value: &str,
-) -> Result<(), VMError> {
-    let value = memory.load(value)?.clone();
-    let index = memory.load_index(index)?;
-    let array_output = memory.load_mut(array_output)?;
+fn insert(memory: &mut Memory, array: Slot, index: Slot, input: Slot) -> Result<(), VMError> {
+    let value = memory.load_slot(input)?.clone();
+    let index = memory.insertion_index(array, index)?;
+    memory.check_element(array, &value, Some(input))?;
+    let added = element_size(&value);
+    memory.check_size(0, added)?;
+    let array_output = memory.load_slot_mut(array)?;
     match (array_output, value) {
         (Value::ArrayOfInteger(array_output), Value::Integer(value)) => {
-            vector_insert(array_output, value, index)?;
+            Rc::make_mut(array_output).insert(index, value);
         }
         (Value::ArrayOfNumber(array_output), Value::Number(value)) => {
-            vector_insert(array_output, value, index)?;
+            Rc::make_mut(array_output).insert(index, value);
         }
         (Value::ArrayOfString(array_output), Value::String(value)) => {
-            vector_insert(array_output, value, index)?;
+            Rc::make_mut(array_output).insert(index, Rc::unwrap_or_clone(value));
         }
         (Value::ArrayOfBoolean(array_output), Value::Boolean(value)) => {
-            vector_insert(array_output, value, index)?;
+            Rc::make_mut(array_output).insert(index, value);
         }
-        (a, b) => return Err(VMError::MismatchingTypes(a.clone(), b)),
+        _ => unreachable!("Checked by check_element"),
     };
+    memory.resize(0, added);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routine(name: &str, lines: &[&str]) -> Routine {
+        let mut routine = Routine::new(String::from(name));
+        for line in lines {
+            let instruction = Instruction::parse(line).expect("Valid instruction");
+            routine.instructions.push(instruction);
+        }
+        routine
+    }
+
+    fn run(memory: &mut Memory, routines: &Routines, line: &str) -> Result<String, VMError> {
+        let mut output = String::new();
+        let instruction = Instruction::parse(line).expect("Valid instruction");
+        execute(memory, routines, &instruction, &mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn test_deep_recursion() {
+        let mut memory = Memory::default();
+        let mut routines = Routines::new();
+        routines.insert(
+            String::from("count"),
+            routine(
+                "count",
+                &[
+                    "ADD i one i",
+                    "COMPARE_LESS i end keep_going",
+                    "CALL_IF count keep_going",
+                ],
+            ),
+        );
+        memory.store("i", Value::Integer(0));
+        memory.store("one", Value::Integer(1));
+        memory.store("end", Value::Integer(100000));
+
+        run(&mut memory, &routines, "CALL count").expect("No stack overflow");
+        assert_eq!(memory.load("i").unwrap(), &Value::Integer(100000));
+    }
+
+    #[test]
+    fn test_redefined_routine() {
+        let mut memory = Memory::default();
+        let mut routines = Routines::new();
+        routines.insert(String::from("outer"), routine("outer", &["CALL inner"]));
+        routines.insert(String::from("inner"), routine("inner", &["PRINT a"]));
+        memory.store("a", Value::Integer(1));
+        memory.store("b", Value::Integer(2));
+        assert_eq!(run(&mut memory, &routines, "CALL outer").unwrap(), "1");
+
+        routines.insert(String::from("inner"), routine("inner", &["PRINT b"]));
+        assert_eq!(run(&mut memory, &routines, "CALL outer").unwrap(), "2");
+    }
+
+    #[test]
+    fn test_routine_used_with_another_memory() {
+        let mut routines = Routines::new();
+        routines.insert(String::from("print"), routine("print", &["PRINT b"]));
+
+        let mut memory = Memory::default();
+        memory.store("b", Value::Integer(1));
+        assert_eq!(run(&mut memory, &routines, "CALL print").unwrap(), "1");
+
+        let mut memory = Memory::default();
+        memory.store("a", Value::Integer(2));
+        memory.store("b", Value::Integer(3));
+        assert_eq!(run(&mut memory, &routines, "CALL print").unwrap(), "3");
+    }
+
+    #[test]
+    fn test_missing_names() {
+        let mut memory = Memory::default();
+        let mut routines = Routines::new();
+        routines.insert(String::from("caller"), routine("caller", &["CALL missing"]));
+
+        match run(&mut memory, &routines, "CALL caller") {
+            Err(VMError::RoutineDoesNotExist(name)) => assert_eq!(name, "missing"),
+            _ => panic!("The routine does not exist"),
+        }
+        match run(&mut memory, &routines, "CALL other") {
+            Err(VMError::RoutineDoesNotExist(name)) => assert_eq!(name, "other"),
+            _ => panic!("The routine does not exist"),
+        }
+        match run(&mut memory, &routines, "PRINT nothing") {
+            Err(VMError::VariableDoesNotExist(name)) => assert_eq!(name, "nothing"),
+            _ => panic!("The variable does not exist"),
+        }
+
+        memory.store("freed", Value::Integer(1));
+        run(&mut memory, &routines, "FREE freed").expect("The variable exists");
+        assert!(memory.load("freed").is_err());
+        assert_eq!(memory.iter().count(), 0);
+        assert!(!routines.contains_key("missing"));
+        assert_eq!(routines.keys().collect::<Vec<_>>(), vec!["caller"]);
+    }
+
+    #[test]
+    fn test_copies_are_independent() {
+        let mut memory = Memory::default();
+        let routines = Routines::new();
+        memory.store("a", Value::from(vec![1, 2]));
+        memory.store("s", Value::from("top"));
+        memory.store("three", Value::Integer(3));
+        memory.store("one", Value::Integer(1));
+
+        run(&mut memory, &routines, "COPY a b").unwrap();
+        run(&mut memory, &routines, "PUSH_BACK a three").unwrap();
+        run(&mut memory, &routines, "STORE_AT b one INTEGER(5)").unwrap();
+        assert_eq!(memory.load("a").unwrap(), &Value::from(vec![1, 2, 3]));
+        assert_eq!(memory.load("b").unwrap(), &Value::from(vec![1, 5]));
+
+        run(&mut memory, &routines, "COPY s t").unwrap();
+        run(&mut memory, &routines, "CONCAT s s s").unwrap();
+        assert_eq!(memory.load("s").unwrap(), &Value::from("toptop"));
+        assert_eq!(memory.load("t").unwrap(), &Value::from("top"));
+    }
+
+    #[test]
+    fn test_stored_literal_is_not_modified() {
+        let mut memory = Memory::default();
+        let mut routines = Routines::new();
+        routines.insert(
+            String::from("fill"),
+            routine(
+                "fill",
+                &["STORE a ARRAY_OF_INTEGER(1)", "PUSH_BACK a one", "PRINT a"],
+            ),
+        );
+        memory.store("one", Value::Integer(1));
+        assert_eq!(run(&mut memory, &routines, "CALL fill").unwrap(), "[1, 1]");
+        assert_eq!(run(&mut memory, &routines, "CALL fill").unwrap(), "[1, 1]");
+    }
+
+    #[test]
+    fn test_error_messages() {
+        let mut memory = Memory::default();
+        let routines = Routines::new();
+        memory.store("array", Value::from(vec![1, 2, 3]));
+        memory.store("big", Value::from((0..1000).collect::<Vec<i64>>()));
+        memory.store("one", Value::Integer(1));
+        memory.store("five", Value::Integer(5));
+        memory.store("half", Value::Number(0.5));
+        memory.store("yes", Value::Boolean(true));
+        memory.store("text", Value::from("a long piece of text"));
+
+        for (line, message) in [
+            (
+                "ADD array one result",
+                "Expected two INTEGER or two NUMBER but `array` = ARRAY_OF_INTEGER(1,2,3) and `one` = INTEGER(1)",
+            ),
+            (
+                "STORE_AT array one BOOLEAN(true)",
+                "Mismatching types, `array` = ARRAY_OF_INTEGER(1,2,3) and BOOLEAN(true)",
+            ),
+            (
+                "PUSH_BACK big half",
+                "Mismatching types, `big` = ARRAY_OF_INTEGER(0,1,2,3,4,5,6,7,...) and `half` = NUMBER(0.5)",
+            ),
+            (
+                "COMPARE_EQUAL one text result",
+                "Mismatching types, `one` = INTEGER(1) and `text` = STRING(\"a long p\"...)",
+            ),
+            (
+                "GET_AT array five result",
+                "Index `five` = INTEGER(5) is out of bound, `array` = ARRAY_OF_INTEGER(1,2,3) has 3 elements",
+            ),
+            (
+                "GET_AT array half result",
+                "An index must be an INTEGER but `half` = NUMBER(0.5)",
+            ),
+            ("SIZE one result", "Expected an array but `one` = INTEGER(1)"),
+            ("LOGICAL_NOT one result", "Expected a BOOLEAN but `one` = INTEGER(1)"),
+            ("LOGICAL_AND yes one result", "Expected a BOOLEAN but `one` = INTEGER(1)"),
+        ] {
+            let error = run(&mut memory, &routines, line).unwrap_err();
+            assert_eq!(error.to_string(), message, "{}", line);
+        }
+
+        // The failed instructions did not modify anything
+        assert_eq!(memory.load("array").unwrap(), &Value::from(vec![1, 2, 3]));
+        assert_eq!(
+            memory.load("big").unwrap(),
+            &Value::from((0..1000).collect::<Vec<i64>>())
+        );
+        match run(&mut memory, &routines, "COPY_AT array one yes").unwrap_err() {
+            VMError::MismatchingTypes(array, element) => {
+                assert_eq!(array.name(), Some("array"));
+                assert_eq!(array.type_name(), "ARRAY_OF_INTEGER");
+                assert_eq!(element.name(), Some("yes"));
+                assert_eq!(element.type_name(), "BOOLEAN");
+            }
+            error => panic!("Unexpected error: {}", error),
+        }
+    }
+
+    #[test]
+    fn test_indices() {
+        let mut memory = Memory::default();
+        let routines = Routines::new();
+        let array = |memory: &Memory| memory.load("array").unwrap().clone();
+        let run_at = |memory: &mut Memory, line: &str, index: i64| {
+            memory.store("index", Value::Integer(index));
+            run(memory, &routines, line)
+        };
+        memory.store("array", Value::from(vec![10, 20, 30]));
+        memory.store("value", Value::Integer(0));
+
+        for (index, expected) in [(0, 10), (2, 30), (-1, 30), (-3, 10)] {
+            run_at(&mut memory, "GET_AT array index result", index).unwrap();
+            assert_eq!(memory.load("result").unwrap(), &Value::Integer(expected));
+        }
+        for index in [3, -4, i64::MIN] {
+            let error = run_at(&mut memory, "GET_AT array index result", index).unwrap_err();
+            assert!(
+                matches!(error, VMError::IndexOutOfBound { array_size: 3, index: i, .. } if i.preview() == Value::Integer(index).literal())
+            );
+        }
+
+        run_at(&mut memory, "STORE_AT array index INTEGER(1)", 0).unwrap();
+        run_at(&mut memory, "COPY_AT array index value", -1).unwrap();
+        assert_eq!(array(&memory), Value::from(vec![1, 20, 0]));
+        assert!(run_at(&mut memory, "STORE_AT array index INTEGER(1)", 3).is_err());
+        assert!(run_at(&mut memory, "COPY_AT array index value", -4).is_err());
+
+        // An element can be inserted after the last one
+        memory.store("array", Value::from(vec![10, 20]));
+        run_at(&mut memory, "INSERT array index value", 2).unwrap();
+        run_at(&mut memory, "INSERT array index value", -1).unwrap();
+        run_at(&mut memory, "INSERT array index value", -4).unwrap();
+        assert_eq!(array(&memory), Value::from(vec![0, 10, 20, 0, 0]));
+        assert!(run_at(&mut memory, "INSERT array index value", 6).is_err());
+        assert!(run_at(&mut memory, "INSERT array index value", -6).is_err());
+
+        run_at(&mut memory, "ERASE array index", 0).unwrap();
+        run_at(&mut memory, "ERASE array index", -1).unwrap();
+        assert_eq!(array(&memory), Value::from(vec![10, 20, 0]));
+        assert!(run_at(&mut memory, "ERASE array index", 3).is_err());
+        assert!(run_at(&mut memory, "ERASE array index", -4).is_err());
+
+        run_at(&mut memory, "RESIZE array index", -1).unwrap();
+        assert_eq!(array(&memory), Value::from(vec![10, 20]));
+        assert!(run_at(&mut memory, "RESIZE array index", -3).is_err());
+        run_at(&mut memory, "RESIZE array index", -2).unwrap();
+        assert_eq!(array(&memory), Value::from(Vec::<i64>::new()));
+        run_at(&mut memory, "RESIZE array index", 2).unwrap();
+        assert_eq!(array(&memory), Value::from(vec![0, 0]));
+        run_at(&mut memory, "RESIZE array index", 0).unwrap();
+        assert_eq!(array(&memory), Value::from(Vec::<i64>::new()));
+        assert!(run_at(&mut memory, "GET_AT array index result", 0).is_err());
+        assert!(run_at(&mut memory, "GET_AT array index result", -1).is_err());
+    }
+
+    #[test]
+    fn test_integer_errors() {
+        let mut memory = Memory::default();
+        let routines = Routines::new();
+        memory.store("zero", Value::Integer(0));
+        memory.store("max", Value::Integer(i64::MAX));
+        memory.store("min", Value::Integer(i64::MIN));
+        memory.store("minus_one", Value::Integer(-1));
+
+        for (line, message) in [
+            (
+                "DIVIDE max zero result",
+                "Division by zero, `zero` = INTEGER(0)",
+            ),
+            (
+                "MODULO max zero result",
+                "Division by zero, `zero` = INTEGER(0)",
+            ),
+            (
+                "ADD max max result",
+                "The result of `ADD` with `max` = INTEGER(9223372036854775807) and `max` = INTEGER(9223372036854775807) is too big for an INTEGER",
+            ),
+            (
+                "DIVIDE min minus_one result",
+                "The result of `DIVIDE` with `min` = INTEGER(-9223372036854775808) and `minus_one` = INTEGER(-1) is too big for an INTEGER",
+            ),
+        ] {
+            let error = run(&mut memory, &routines, line).unwrap_err();
+            assert_eq!(error.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn test_step_limit() {
+        let mut memory = Memory::default();
+        let mut routines = Routines::new();
+        routines.insert(
+            String::from("forever"),
+            routine("forever", &["CALL_IF forever yes"]),
+        );
+        memory.store("yes", Value::Boolean(true));
+
+        let mut limits = Limits {
+            max_steps: Some(100),
+            ..Limits::default()
+        };
+        let instruction = Instruction::parse("CALL forever").unwrap();
+        let mut output = String::new();
+        match execute_with_limits(
+            &mut memory,
+            &routines,
+            &instruction,
+            &mut output,
+            &mut limits,
+        ) {
+            Err(VMError::StepLimitExceeded(100)) => (),
+            _ => panic!("The loop never ends"),
+        }
+        assert_eq!(limits.steps(), 100);
+
+        // The budget is shared with the next instructions
+        let instruction = Instruction::parse("PRINT yes").unwrap();
+        assert!(execute_with_limits(
+            &mut memory,
+            &routines,
+            &instruction,
+            &mut output,
+            &mut limits
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_call_depth_limit() {
+        let mut memory = Memory::default();
+        let mut routines = Routines::new();
+        routines.insert(String::from("a"), routine("a", &["CALL b"]));
+        routines.insert(String::from("b"), routine("b", &["CALL a"]));
+
+        let mut limits = Limits {
+            max_call_depth: Some(3),
+            ..Limits::default()
+        };
+        let instruction = Instruction::parse("CALL a").unwrap();
+        let mut output = String::new();
+        let error = execute_with_limits(
+            &mut memory,
+            &routines,
+            &instruction,
+            &mut output,
+            &mut limits,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Calling `b` goes over the maximum call depth of 3, the last calls are: a -> b -> a"
+        );
+    }
+
+    #[test]
+    fn test_memory_size_follows_the_values() {
+        let mut memory = Memory::default();
+        let routines = Routines::new();
+        memory.store("one", Value::Integer(1));
+        memory.store("ten", Value::Integer(10));
+        memory.store("word", Value::from("word"));
+        for line in [
+            "STORE strings ARRAY_OF_STRING(\"a\",\"bb\")",
+            "PUSH_BACK strings word",
+            "INSERT strings one word",
+            "STORE_AT strings one STRING(\"longer string\")",
+            "ERASE strings one",
+            "RESIZE strings ten",
+            "RESIZE strings one",
+            "STORE integers ARRAY_OF_INTEGER(1,2,3)",
+            "RESIZE integers ten",
+            "COPY integers copied",
+            "PUSH_BACK copied one",
+            "CONCAT word word word",
+            "FREE copied",
+        ] {
+            run(&mut memory, &routines, line).expect("Valid instruction");
+            let expected: usize = memory.iter().map(|(_, value)| value_size(value)).sum();
+            assert_eq!(memory.size(), expected, "after {}", line);
+        }
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let mut memory = Memory::default();
+        let routines = Routines::new();
+        memory.set_max_size(Some(10_000));
+        memory.store("huge", Value::Integer(1_000_000_000_000));
+        memory.store("text", Value::from("0123456789"));
+
+        run(&mut memory, &routines, "STORE array ARRAY_OF_INTEGER(0)").unwrap();
+        match run(&mut memory, &routines, "RESIZE array huge") {
+            Err(VMError::MemoryLimitExceeded { max_size, .. }) => assert_eq!(max_size, 10_000),
+            _ => panic!("The array is too big"),
+        }
+        assert_eq!(memory.load("array").unwrap(), &Value::from(vec![0]));
+
+        let mut concatenations = 0;
+        while run(&mut memory, &routines, "CONCAT text text text").is_ok() {
+            concatenations += 1;
+        }
+        assert_eq!(concatenations, 9);
+        assert!(memory.size() <= 10_000);
+
+        // Freeing memory makes room again
+        run(&mut memory, &routines, "FREE text").unwrap();
+        run(&mut memory, &routines, "STORE text STRING(\"small\")").unwrap();
+    }
+
+    #[test]
+    fn test_cancellation() {
+        let mut memory = Memory::default();
+        let mut routines = Routines::new();
+        routines.insert(
+            String::from("forever"),
+            routine("forever", &["CALL_IF forever yes"]),
+        );
+        memory.store("yes", Value::Boolean(true));
+        let instruction = Instruction::parse("CALL forever").unwrap();
+        let mut output = String::new();
+
+        let cancellation = CancellationToken::new();
+        let mut limits = Limits {
+            cancellation: Some(cancellation.clone()),
+            ..Limits::default()
+        };
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            cancellation.cancel();
+        });
+        match execute_with_limits(
+            &mut memory,
+            &routines,
+            &instruction,
+            &mut output,
+            &mut limits,
+        ) {
+            Err(VMError::Cancelled) => (),
+            _ => panic!("The execution is cancelled"),
+        }
+        canceller.join().unwrap();
+
+        let mut limits = Limits {
+            deadline: Some(Instant::now() + std::time::Duration::from_millis(10)),
+            ..Limits::default()
+        };
+        match execute_with_limits(
+            &mut memory,
+            &routines,
+            &instruction,
+            &mut output,
+            &mut limits,
+        ) {
+            Err(VMError::Timeout) => (),
+            _ => panic!("The execution takes too much time"),
+        }
+    }
+
+    #[test]
+    fn test_observer() {
+        #[derive(Default)]
+        struct Recorder {
+            events: Vec<String>,
+        }
+
+        impl Observer for Recorder {
+            fn after(&mut self, event: &Event, memory: &Memory) {
+                let i = memory
+                    .load("i")
+                    .map(ToString::to_string)
+                    .unwrap_or_default();
+                self.events.push(format!(
+                    "{} {} {} i={}",
+                    event.line,
+                    event.call_stack.join("/"),
+                    event.instruction.name(),
+                    i
+                ));
+            }
+
+            fn failed(&mut self, event: &Event, _memory: &Memory, error: &VMError) {
+                let name = event.instruction.name();
+                self.events.push(format!("{} failed: {}", name, error));
+            }
+        }
+
+        let mut memory = Memory::default();
+        let mut routines = Routines::new();
+        let mut inner = routine("inner", &["STORE i INTEGER(1)"]);
+        inner.lines.push(2);
+        routines.insert(String::from("inner"), inner);
+        routines.insert(String::from("outer"), routine("outer", &["CALL inner"]));
+
+        let mut recorder = Recorder::default();
+        let instruction = Instruction::parse("CALL outer").unwrap();
+        execute_observed(
+            &mut memory,
+            &routines,
+            &instruction,
+            7,
+            &mut String::new(),
+            &mut Limits::default(),
+            &mut recorder,
+        )
+        .unwrap();
+        assert_eq!(
+            recorder.events,
+            vec!["7  CALL i=", "0 outer CALL i=", "2 outer/inner STORE i=1"]
+        );
+
+        // The failed instruction is notified instead of `after`
+        recorder.events.clear();
+        let instruction = Instruction::parse("PRINT nope").unwrap();
+        let result = execute_observed(
+            &mut memory,
+            &routines,
+            &instruction,
+            8,
+            &mut String::new(),
+            &mut Limits::default(),
+            &mut recorder,
+        );
+        assert!(result.is_err());
+        assert_eq!(
+            recorder.events,
+            vec!["PRINT failed: Variable `nope` does not exist"]
+        );
+    }
+
+    #[test]
+    fn test_deep_observed_recursion() {
+        #[derive(Default)]
+        struct Depth {
+            max: usize,
+            events: usize,
+        }
+
+        impl Observer for Depth {
+            fn before(&mut self, event: &Event, _memory: &Memory) {
+                assert!(event.routine().is_none_or(|name| name == "count"));
+                self.max = self.max.max(event.call_stack.len());
+                self.events += 1;
+            }
+        }
+
+        let mut memory = Memory::default();
+        let mut routines = Routines::new();
+        routines.insert(
+            String::from("count"),
+            routine(
+                "count",
+                &[
+                    "ADD i one i",
+                    "COMPARE_LESS i end keep_going",
+                    "CALL_IF count keep_going",
+                ],
+            ),
+        );
+        memory.store("i", Value::Integer(0));
+        memory.store("one", Value::Integer(1));
+        memory.store("end", Value::Integer(100000));
+
+        // Takes as long as an unobserved run, the call stack is not rebuilt at each instruction
+        let mut depth = Depth::default();
+        let instruction = Instruction::parse("CALL count").unwrap();
+        execute_observed(
+            &mut memory,
+            &routines,
+            &instruction,
+            1,
+            &mut String::new(),
+            &mut Limits::default(),
+            &mut depth,
+        )
+        .unwrap();
+        assert_eq!(memory.load("i").unwrap(), &Value::Integer(100000));
+        assert_eq!(depth.max, 100000);
+        assert_eq!(depth.events, 1 + 3 * 100000);
+    }
+
+    #[test]
+    fn test_array_algorithms() {
+        let mut memory = Memory::default();
+        let routines = Routines::new();
+        let load = |memory: &Memory, name: &str| memory.load(name).unwrap().clone();
+        memory.store("a", Value::from(vec![1, 2]));
+        memory.store("b", Value::from(vec![3]));
+        memory.store("text", Value::from("3"));
+        memory.store("three", Value::Integer(3));
+        memory.store("four", Value::Integer(4));
+
+        run(&mut memory, &routines, "CONCAT a b c").unwrap();
+        run(&mut memory, &routines, "CONCAT a a a").unwrap();
+        assert_eq!(load(&memory, "c"), Value::from(vec![1, 2, 3]));
+        assert_eq!(load(&memory, "a"), Value::from(vec![1, 2, 1, 2]));
+        let error = run(&mut memory, &routines, "CONCAT three b c").unwrap_err();
+        assert!(matches!(error, VMError::ExpectedConcatenable(_)));
+        let error = run(&mut memory, &routines, "CONCAT text b c").unwrap_err();
+        assert!(matches!(error, VMError::MismatchingTypes(_, _)));
+
+        // Equal numbers keep their order, -0.0 stays before 0.0
+        memory.store(
+            "numbers",
+            Value::from(vec![0.0, f64::NAN, -0.0, -1.0, f64::NAN]),
+        );
+        run(&mut memory, &routines, "SORT numbers").unwrap();
+        match load(&memory, "numbers") {
+            Value::ArrayOfNumber(numbers) => {
+                assert_eq!(&numbers[..3], &[-1.0, 0.0, -0.0]);
+                assert!(numbers[1].is_sign_positive() && numbers[2].is_sign_negative());
+                assert!(numbers[3].is_nan() && numbers[4].is_nan());
+            }
+            other => panic!("Not sorted in place: {:?}", other),
+        }
+
+        for (line, expected) in [
+            ("INDEX_OF a four result", Value::Integer(-1)),
+            ("INDEX_OF c three result", Value::Integer(2)),
+            ("CONTAINS c three result", Value::Boolean(true)),
+        ] {
+            run(&mut memory, &routines, line).unwrap();
+            assert_eq!(load(&memory, "result"), expected, "{}", line);
+        }
+        let error = run(&mut memory, &routines, "CONTAINS c text result").unwrap_err();
+        assert!(matches!(error, VMError::MismatchingTypes(_, _)));
+
+        let slice = |memory: &mut Memory, start: i64, end: i64| {
+            memory.store("start", Value::Integer(start));
+            memory.store("end", Value::Integer(end));
+            run(memory, &routines, "SLICE c start end slice").map(|_| load(memory, "slice"))
+        };
+        assert_eq!(slice(&mut memory, 1, 3).unwrap(), Value::from(vec![2, 3]));
+        assert_eq!(slice(&mut memory, -2, -1).unwrap(), Value::from(vec![2]));
+        assert_eq!(
+            slice(&mut memory, 2, 1).unwrap(),
+            Value::from(Vec::<i64>::new())
+        );
+        assert!(slice(&mut memory, 0, 4).is_err());
+
+        run(&mut memory, &routines, "RANGE three three range").unwrap();
+        assert_eq!(load(&memory, "range"), Value::from(Vec::<i64>::new()));
+        let error = run(&mut memory, &routines, "RANGE text three range").unwrap_err();
+        assert!(matches!(error, VMError::ExpectedInteger(_)));
+
+        // The strings put by FILL are counted
+        memory.store("strings", Value::from(vec![String::new(); 3]));
+        memory.store("long", Value::from("a".repeat(100)));
+        let size = memory.size();
+        run(&mut memory, &routines, "FILL strings long").unwrap();
+        assert_eq!(memory.size(), size + 300);
+        run(&mut memory, &routines, "REVERSE c").unwrap();
+        assert_eq!(load(&memory, "c"), Value::from(vec![3, 2, 1]));
+        assert!(run(&mut memory, &routines, "SORT text").is_err());
+    }
+
+    #[test]
+    fn test_range_memory_limit() {
+        let mut memory = Memory::default();
+        let routines = Routines::new();
+        memory.set_max_size(Some(10_000));
+        memory.store("start", Value::Integer(0));
+        memory.store("end", Value::Integer(1_000_000));
+
+        // Refused before the array is built
+        let error = run(&mut memory, &routines, "RANGE start end range").unwrap_err();
+        assert!(matches!(error, VMError::MemoryLimitExceeded { .. }));
+        assert!(memory.load("range").is_err());
+
+        // Too many elements to count their bytes, the number of elements is reported instead
+        memory.store("end", Value::Integer(i64::MAX));
+        let error = run(&mut memory, &routines, "RANGE start end range").unwrap_err();
+        assert!(matches!(error, VMError::AllocationFailed(size) if size == i64::MAX as usize));
+
+        // Refused even without memory limit instead of aborting the process
+        memory.set_max_size(None);
+        memory.store("end", Value::Integer(1_000_000_000_000));
+        let error = run(&mut memory, &routines, "RANGE start end range").unwrap_err();
+        assert!(matches!(
+            error,
+            VMError::AllocationFailed(1_000_000_000_000)
+        ));
+        memory.store("end", Value::Integer(i64::MAX));
+        let error = run(&mut memory, &routines, "RANGE start end range").unwrap_err();
+        assert!(matches!(error, VMError::AllocationFailed(_)));
+        assert!(memory.load("range").is_err());
+    }
+
+    #[test]
+    fn test_resize_and_fill_allocation() {
+        let mut memory = Memory::default();
+        let routines = Routines::new();
+        memory.store("array", Value::from(vec![1, 2]));
+        memory.store("strings", Value::from(vec![String::new(); 1 << 20]));
+        memory.store("long", Value::from("a".repeat(1 << 12)));
+
+        // Refused instead of aborting the process, the array is left as it was
+        for size in [1 << 40, 1 << 62] {
+            memory.store("size", Value::Integer(size));
+            let error = run(&mut memory, &routines, "RESIZE array size").unwrap_err();
+            assert!(matches!(error, VMError::AllocationFailed(s) if s == size as usize));
+        }
+        assert_eq!(memory.load("array").unwrap(), &Value::from(vec![1, 2]));
+        let error = run(&mut memory, &routines, "FILL strings long").unwrap_err();
+        assert!(matches!(error, VMError::AllocationFailed(size) if size == 1 << 20));
+
+        // The number of elements is reported instead of a number of bytes too big to be counted
+        memory.set_max_size(Some(10_000));
+        memory.store("size", Value::Integer(1 << 62));
+        let error = run(&mut memory, &routines, "RESIZE array size").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Cannot allocate an array of 4611686018427387904 elements"
+        );
+    }
+
+    #[test]
+    fn test_type_instructions() {
+        let mut memory = Memory::default();
+        let routines = Routines::new();
+        memory.store("array", Value::from(vec![1.5]));
+        memory.store("text", Value::from("[1.5]"));
+
+        run(&mut memory, &routines, "TYPE_OF array type").unwrap();
+        assert_eq!(
+            memory.load("type").unwrap(),
+            &Value::from("ARRAY_OF_NUMBER")
+        );
+        run(&mut memory, &routines, "TYPE_OF type type").unwrap();
+        assert_eq!(memory.load("type").unwrap(), &Value::from("STRING"));
+        assert!(run(&mut memory, &routines, "TYPE_OF missing type").is_err());
+
+        for (line, expected) in [
+            ("IS_ARRAY array result", true),
+            ("IS_ARRAY text result", false),
+            ("IS_DEFINED text result", true),
+            ("IS_DEFINED missing result", false),
+        ] {
+            run(&mut memory, &routines, line).unwrap();
+            assert_eq!(memory.load("result").unwrap(), &Value::Boolean(expected));
+        }
+        run(&mut memory, &routines, "FREE text").unwrap();
+        run(&mut memory, &routines, "IS_DEFINED text result").unwrap();
+        assert_eq!(memory.load("result").unwrap(), &Value::Boolean(false));
+        assert!(run(&mut memory, &routines, "IS_ARRAY missing result").is_err());
+    }
+
+    #[test]
+    fn test_assertions() {
+        let mut memory = Memory::default();
+        let routines = Routines::new();
+        memory.store("yes", Value::Boolean(true));
+        memory.store("no", Value::Boolean(false));
+        memory.store("one", Value::Integer(1));
+        memory.store("two", Value::Integer(2));
+        memory.store("text", Value::from("1"));
+
+        run(&mut memory, &routines, "ASSERT yes").unwrap();
+        run(&mut memory, &routines, "ASSERT_EQUAL one one").unwrap();
+        let error = run(&mut memory, &routines, "ASSERT no").unwrap_err();
+        assert_eq!(error.to_string(), "Assertion failed: `no` is false");
+        let error = run(&mut memory, &routines, "ASSERT one").unwrap_err();
+        assert!(matches!(error, VMError::ExpectedBoolean(_)));
+        let error = run(&mut memory, &routines, "ASSERT_EQUAL one two").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Assertion failed: `one` = INTEGER(1) but `two` = INTEGER(2)"
+        );
+        let error = run(&mut memory, &routines, "ASSERT_EQUAL one text").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Assertion failed: `one` = INTEGER(1) but `text` = STRING(\"1\")"
+        );
+    }
+
+    #[test]
+    fn test_assert_error() {
+        let mut memory = Memory::default();
+        let mut routines = Routines::new();
+        routines.insert(
+            String::from("fails"),
+            routine(
+                "fails",
+                &["STORE i INTEGER(1)", "PRINT missing", "STORE i INTEGER(2)"],
+            ),
+        );
+        routines.insert(
+            String::from("calls_fails"),
+            routine("calls_fails", &["CALL fails"]),
+        );
+        routines.insert(
+            String::from("works"),
+            routine("works", &["STORE i INTEGER(3)"]),
+        );
+        routines.insert(
+            String::from("test"),
+            routine(
+                "test",
+                &["ASSERT_ERROR fails", "ASSERT_ERROR calls_fails", "PRINT i"],
+            ),
+        );
+        routines.insert(String::from("loops"), routine("loops", &["CALL loops"]));
+
+        // The error is caught and the execution goes on after the ASSERT_ERROR
+        assert_eq!(run(&mut memory, &routines, "CALL test").unwrap(), "1");
+        let error = run(&mut memory, &routines, "ASSERT_ERROR works").unwrap_err();
+        assert_eq!(error.to_string(), "Assertion failed: `works` did not fail");
+        // Caught by an outer ASSERT_ERROR
+        routines.insert(
+            String::from("nested"),
+            routine("nested", &["ASSERT_ERROR works"]),
+        );
+        run(&mut memory, &routines, "ASSERT_ERROR nested").unwrap();
+
+        // The limits are not caught
+        let mut limits = Limits {
+            max_steps: Some(100),
+            ..Limits::default()
+        };
+        let instruction = Instruction::parse("ASSERT_ERROR loops").unwrap();
+        let error = execute_with_limits(
+            &mut memory,
+            &routines,
+            &instruction,
+            &mut String::new(),
+            &mut limits,
+        )
+        .unwrap_err();
+        assert!(matches!(error, VMError::StepLimitExceeded(100)));
+    }
+}
@@ -0,0 +1,51 @@
+use topflight_definitions::Instruction;
+
+use crate::{Memory, VMError};
+
+// An instruction about to be executed, or just executed
+pub struct Event<'a> {
+    pub instruction: &'a Instruction,
+    // Source line, 0 when unknown
+    pub line: usize,
+    // Routines being executed, the current one last, empty at the top level
+    pub call_stack: &'a [&'a str],
+}
+
+impl Event<'_> {
+    pub fn routine(&self) -> Option<&str> {
+        self.call_stack.last().copied()
+    }
+}
+
+// Notified around every executed instruction, to trace, profile or measure the coverage of a
+// program. The memory can be read to know the values used by the instruction.
+pub trait Observer {
+    fn before(&mut self, _event: &Event, _memory: &Memory) {}
+
+    // Not called if the instruction failed, `failed` is called instead
+    fn after(&mut self, _event: &Event, _memory: &Memory) {}
+
+    // The execution stops with this error
+    fn failed(&mut self, _event: &Event, _memory: &Memory, _error: &VMError) {}
+}
+
+// Several observers, notified in order
+impl Observer for Vec<&mut dyn Observer> {
+    fn before(&mut self, event: &Event, memory: &Memory) {
+        for observer in self.iter_mut() {
+            observer.before(event, memory);
+        }
+    }
+
+    fn after(&mut self, event: &Event, memory: &Memory) {
+        for observer in self.iter_mut() {
+            observer.after(event, memory);
+        }
+    }
+
+    fn failed(&mut self, event: &Event, memory: &Memory, error: &VMError) {
+        for observer in self.iter_mut() {
+            observer.failed(event, memory, error);
+        }
+    }
+}
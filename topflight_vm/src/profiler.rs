@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use topflight_definitions::{CallIf, Instruction, Value};
+
+use crate::{Event, Memory, Observer, VMError};
+
+// Number of lines in the report, the hottest ones
+const REPORTED_LINES: usize = 20;
+
+// Name of the root of the folded stacks, for the top level instructions
+const TOP_LEVEL: &str = "(top level)";
+
+#[derive(Default, Clone, Copy)]
+struct Stats {
+    count: u64,
+    time: Duration,
+}
+
+#[derive(Default)]
+struct RoutineStats {
+    name: String,
+    calls: u64,
+    // Instructions of the routine itself
+    own: Stats,
+    // Time spent in the routine and the routines it called
+    total: Duration,
+    // Number of times the routine is in the call stack, it is recursive when more than 1
+    active: usize,
+}
+
+// Counts the executed instructions and measures their time by instruction kind, by routine and
+// by line. The time of an instruction is measured around it, without the time of the VM between
+// two instructions.
+#[derive(Default)]
+pub struct Profiler {
+    instructions: HashMap<&'static str, Stats>,
+    lines: HashMap<usize, Stats>,
+    routines: Vec<RoutineStats>,
+    routine_indices: HashMap<String, usize>,
+    // Same as the call stack of the VM, with the index of the routines
+    call_stack: Vec<usize>,
+    // Routines in the call stack, each once
+    active_routines: Vec<usize>,
+    // The call stack without the recursive calls, with their number
+    folded_stack: Vec<(usize, usize)>,
+    folded_stack_key: String,
+    folded_stacks: HashMap<String, Duration>,
+    start: Option<Instant>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    // Sorted by time, the slowest first
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+
+        let mut instructions: Vec<_> = self.instructions.iter().collect();
+        instructions
+            .sort_by(|(a_name, a), (b_name, b)| b.time.cmp(&a.time).then(a_name.cmp(b_name)));
+        report += "Instructions:\n      count     time (ms)  instruction\n";
+        for (name, stats) in instructions {
+            let _ = writeln!(
+                report,
+                "{:>11} {:>13}  {}",
+                stats.count,
+                milliseconds(stats.time),
+                name
+            );
+        }
+
+        let mut routines: Vec<_> = self
+            .routines
+            .iter()
+            .filter(|routine| routine.calls > 0)
+            .collect();
+        routines.sort_by(|a, b| b.total.cmp(&a.total).then(a.name.cmp(&b.name)));
+        report +=
+            "\nRoutines:\n      calls  instructions  own time (ms)  total time (ms)  routine\n";
+        for routine in routines {
+            let _ = writeln!(
+                report,
+                "{:>11} {:>13} {:>14} {:>16}  {}",
+                routine.calls,
+                routine.own.count,
+                milliseconds(routine.own.time),
+                milliseconds(routine.total),
+                routine.name
+            );
+        }
+
+        let mut lines: Vec<_> = self.lines.iter().filter(|(line, _)| **line != 0).collect();
+        lines.sort_by(|(a_line, a), (b_line, b)| b.time.cmp(&a.time).then(a_line.cmp(b_line)));
+        report += "\nLines:\n      count     time (ms)  line\n";
+        for (line, stats) in lines.into_iter().take(REPORTED_LINES) {
+            let _ = writeln!(
+                report,
+                "{:>11} {:>13}  {}",
+                stats.count,
+                milliseconds(stats.time),
+                line
+            );
+        }
+        report
+    }
+
+    // One line per call stack with the time spent in it in nanoseconds, the format read by
+    // flamegraph tools. The recursive calls are merged so a loop does not make a huge stack.
+    pub fn folded_stacks(&self) -> String {
+        let mut stacks: Vec<_> = self.folded_stacks.iter().collect();
+        stacks.sort();
+        stacks
+            .into_iter()
+            .map(|(stack, time)| format!("{} {}\n", stack, time.as_nanos()))
+            .collect()
+    }
+
+    fn routine_index(&mut self, name: &str) -> usize {
+        if let Some(index) = self.routine_indices.get(name) {
+            return *index;
+        }
+        let index = self.routines.len();
+        self.routine_indices.insert(name.to_string(), index);
+        self.routines.push(RoutineStats {
+            name: name.to_string(),
+            ..RoutineStats::default()
+        });
+        index
+    }
+
+    // A routine is only called or left at the top of the call stack, so the lengths are enough
+    // to follow it
+    fn follow_call_stack(&mut self, call_stack: &[&str]) {
+        let mut folded_stack_changed = false;
+        while self.call_stack.len() > call_stack.len() {
+            let index = self
+                .call_stack
+                .pop()
+                .expect("Longer than the new call stack");
+            let routine = &mut self.routines[index];
+            routine.active -= 1;
+            if routine.active == 0 {
+                self.active_routines.retain(|active| *active != index);
+            }
+
+            let top = self
+                .folded_stack
+                .last_mut()
+                .expect("Same length as the call stack");
+            top.1 -= 1;
+            if top.1 == 0 {
+                self.folded_stack.pop();
+                folded_stack_changed = true;
+            }
+        }
+
+        for name in &call_stack[self.call_stack.len()..] {
+            let index = self.routine_index(name);
+            self.call_stack.push(index);
+            let routine = &mut self.routines[index];
+            routine.active += 1;
+            if routine.active == 1 {
+                self.active_routines.push(index);
+            }
+
+            match self.folded_stack.last_mut() {
+                Some((top, count)) if *top == index => *count += 1,
+                _ => {
+                    self.folded_stack.push((index, 1));
+                    folded_stack_changed = true;
+                }
+            }
+        }
+
+        if folded_stack_changed || self.folded_stack_key.is_empty() {
+            self.folded_stack_key = String::from(TOP_LEVEL);
+            for (index, _) in self.folded_stack.iter() {
+                self.folded_stack_key.push(';');
+                self.folded_stack_key += &self.routines[*index].name;
+            }
+        }
+    }
+}
+
+impl Observer for Profiler {
+    fn before(&mut self, event: &Event, _memory: &Memory) {
+        self.follow_call_stack(event.call_stack);
+        self.start = Some(Instant::now());
+    }
+
+    fn after(&mut self, event: &Event, memory: &Memory) {
+        self.measure(event, called_routine(event.instruction, memory));
+    }
+
+    // The failed instruction took time too
+    fn failed(&mut self, event: &Event, _memory: &Memory, _error: &VMError) {
+        self.measure(event, None);
+    }
+}
+
+impl Profiler {
+    // The call is counted by the instruction calling the routine, an empty routine has no
+    // instruction of its own. The time of the call goes to the stack of the called routine.
+    fn measure(&mut self, event: &Event, called_routine: Option<&str>) {
+        let time = match self.start.take() {
+            Some(start) => start.elapsed(),
+            None => return,
+        };
+        let add = |stats: &mut Stats| {
+            stats.count += 1;
+            stats.time += time;
+        };
+
+        add(self
+            .instructions
+            .entry(event.instruction.name())
+            .or_default());
+        add(self.lines.entry(event.line).or_default());
+        if let Some(index) = self.call_stack.last() {
+            add(&mut self.routines[*index].own);
+        }
+        for index in self.active_routines.iter() {
+            self.routines[*index].total += time;
+        }
+
+        let mut called_stack_key = None;
+        if let Some(name) = called_routine {
+            let index = self.routine_index(name);
+            let routine = &mut self.routines[index];
+            routine.calls += 1;
+            if routine.active == 0 {
+                routine.total += time;
+            }
+            // A recursive call stays in the same stack
+            if self.folded_stack.last().map(|(top, _)| *top) != Some(index) {
+                called_stack_key = Some(format!("{};{}", self.folded_stack_key, name));
+            }
+        }
+        let key = called_stack_key.as_ref().unwrap_or(&self.folded_stack_key);
+        match self.folded_stacks.get_mut(key) {
+            Some(total) => *total += time,
+            None => {
+                self.folded_stacks.insert(key.clone(), time);
+            }
+        }
+    }
+}
+
+// CALL_IF only calls the routine when its condition is true
+fn called_routine<'a>(instruction: &'a Instruction, memory: &Memory) -> Option<&'a str> {
+    match instruction {
+        Instruction::CallIf(CallIf {
+            routine_name,
+            boolean_input_name,
+        }) => match memory.load(boolean_input_name) {
+            Ok(Value::Boolean(true)) => Some(routine_name),
+            _ => None,
+        },
+        instruction => instruction.called_routine(),
+    }
+}
+
+fn milliseconds(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{execute_observed, Limits, Routines};
+    use topflight_definitions::{Instruction, Routine, Value};
+
+    #[test]
+    fn test_profile() {
+        let mut routines = Routines::new();
+        let mut count = Routine::new(String::from("count"));
+        for line in [
+            "ADD i one i",
+            "COMPARE_LESS i end keep_going",
+            "CALL_IF count keep_going",
+        ] {
+            count.instructions.push(Instruction::parse(line).unwrap());
+            count.lines.push(count.lines.len() + 2);
+        }
+        routines.insert(String::from("count"), count);
+        routines.insert(String::from("empty"), Routine::new(String::from("empty")));
+        let mut memory = Memory::default();
+        memory.store("i", Value::Integer(0));
+        memory.store("one", Value::Integer(1));
+        memory.store("end", Value::Integer(10));
+
+        let mut profiler = Profiler::new();
+        for (line, instruction) in [(1, "CALL count"), (20, "CALL empty")] {
+            execute_observed(
+                &mut memory,
+                &routines,
+                &Instruction::parse(instruction).unwrap(),
+                line,
+                &mut String::new(),
+                &mut Limits::default(),
+                &mut profiler,
+            )
+            .unwrap();
+        }
+
+        let count = &profiler.routines[profiler.routine_indices["count"]];
+        assert_eq!((count.calls, count.own.count), (10, 30));
+        assert_eq!(profiler.instructions["ADD"].count, 10);
+        assert_eq!(profiler.instructions["CALL"].count, 2);
+        // Counted by the instruction calling it
+        let empty = &profiler.routines[profiler.routine_indices["empty"]];
+        assert_eq!((empty.calls, empty.own.count), (1, 0));
+        assert_eq!(profiler.lines[&2].count, 10);
+
+        // The recursion is merged, the time of a call goes to the called routine
+        let folded = profiler.folded_stacks();
+        let stacks: Vec<&str> = folded
+            .lines()
+            .map(|line| line.rsplit_once(' ').unwrap().0)
+            .collect();
+        assert_eq!(stacks, vec!["(top level);count", "(top level);empty"]);
+
+        let report = profiler.report();
+        assert!(report.contains("count\n"));
+        assert!(report.contains("empty\n"));
+        assert!(report.contains("COMPARE_LESS"));
+    }
+}
@@ -0,0 +1,158 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use topflight_definitions::{Instruction, Routine};
+
+use crate::bytecode::{self, Op};
+use crate::{Memory, VMError};
+
+// Every routine has an index, calls are resolved to it once instead of looking up the name each time.
+// A routine can get an index before being defined when another routine calls it.
+#[derive(Default)]
+pub struct Routines {
+    indices: HashMap<String, usize>,
+    names: Vec<String>,
+    routines: Vec<Option<Routine>>,
+    compiled: RefCell<Compiled>,
+}
+
+// The bytecode uses the slots of one memory, it is compiled again for another one
+#[derive(Default)]
+struct Compiled {
+    memory_id: usize,
+    code: Vec<Option<Rc<[Op]>>>,
+}
+
+impl Routines {
+    pub fn new() -> Routines {
+        Routines::default()
+    }
+
+    pub fn insert(&mut self, name: String, routine: Routine) {
+        for instruction in routine.instructions.iter() {
+            if let Some(routine_name) = instruction.called_routine() {
+                self.index(routine_name);
+            }
+        }
+        let index = self.index(&name);
+        self.routines[index] = Some(routine);
+        // Calls are resolved by index so the other routines stay valid
+        self.compiled.get_mut().code.clear();
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Routine> {
+        self.index_of(name)
+            .and_then(|index| self.routines[index].as_ref())
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routines.iter().all(Option::is_none)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.names
+            .iter()
+            .zip(self.routines.iter())
+            .filter(|(_, routine)| routine.is_some())
+            .map(|(name, _)| name)
+    }
+
+    pub fn into_values(self) -> impl Iterator<Item = Routine> {
+        self.routines.into_iter().flatten()
+    }
+
+    pub(crate) fn index_of(&self, name: &str) -> Option<usize> {
+        self.indices.get(name).copied()
+    }
+
+    pub(crate) fn name_of(&self, index: usize) -> &str {
+        self.names[index].as_str()
+    }
+
+    pub(crate) fn instruction(&self, index: usize, position: usize) -> &Instruction {
+        let routine = self.routines[index]
+            .as_ref()
+            .expect("A routine being executed is defined");
+        &routine.instructions[position]
+    }
+
+    // Source line of an instruction of a routine, 0 when unknown
+    pub(crate) fn line_of(&self, index: usize, position: usize) -> usize {
+        self.routines[index]
+            .as_ref()
+            .and_then(|routine| routine.lines.get(position))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    // The bytecode of a routine, compiled the first time it is called
+    pub(crate) fn code(&self, index: usize, memory: &mut Memory) -> Result<Rc<[Op]>, VMError> {
+        let routine = match &self.routines[index] {
+            Some(routine) => routine,
+            None => return Err(VMError::RoutineDoesNotExist(self.names[index].clone())),
+        };
+
+        let mut compiled = self.compiled.borrow_mut();
+        if compiled.memory_id != memory.id() {
+            compiled.memory_id = memory.id();
+            compiled.code.clear();
+        }
+        if compiled.code.len() <= index {
+            compiled.code.resize(self.routines.len(), None);
+        }
+
+        let code = compiled.code[index].get_or_insert_with(|| {
+            routine
+                .instructions
+                .iter()
+                .map(|instruction| bytecode::compile(instruction, memory, self))
+                .collect()
+        });
+        Ok(code.clone())
+    }
+
+    fn index(&mut self, name: &str) -> usize {
+        if let Some(index) = self.indices.get(name) {
+            return *index;
+        }
+        let index = self.routines.len();
+        self.indices.insert(String::from(name), index);
+        self.names.push(String::from(name));
+        self.routines.push(None);
+        index
+    }
+}
+
+impl IntoIterator for Routines {
+    type Item = (String, Routine);
+    type IntoIter = Box<dyn Iterator<Item = (String, Routine)>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(
+            self.names
+                .into_iter()
+                .zip(self.routines)
+                .filter_map(|(name, routine)| routine.map(|routine| (name, routine))),
+        )
+    }
+}
+
+impl Extend<(String, Routine)> for Routines {
+    fn extend<T: IntoIterator<Item = (String, Routine)>>(&mut self, iter: T) {
+        for (name, routine) in iter {
+            self.insert(name, routine);
+        }
+    }
+}
+
+impl FromIterator<(String, Routine)> for Routines {
+    fn from_iter<T: IntoIterator<Item = (String, Routine)>>(iter: T) -> Self {
+        let mut routines = Routines::new();
+        routines.extend(iter);
+        routines
+    }
+}
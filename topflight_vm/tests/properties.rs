@@ -0,0 +1,199 @@
+// Properties of the instructions checked on random values of every type
+use proptest::collection::vec;
+use proptest::prelude::*;
+use topflight_definitions::{Instruction, Value};
+use topflight_vm::{execute, Memory, Routines, VMError};
+
+fn run(memory: &mut Memory, line: &str) -> Result<(), VMError> {
+    let instruction = Instruction::parse(line).expect("Valid instruction");
+    execute(memory, &Routines::new(), &instruction, &mut String::new())
+}
+
+fn load(memory: &Memory, name: &str) -> Value {
+    memory.load(name).expect("Stored by the test").clone()
+}
+
+// Without NaN, which is neither less, equal nor greater than anything
+fn number() -> impl Strategy<Value = f64> {
+    -1e9..1e9f64
+}
+
+fn value() -> impl Strategy<Value = Value> {
+    prop_oneof![
+        any::<i64>().prop_map(Value::Integer),
+        number().prop_map(Value::Number),
+        any::<char>().prop_map(Value::Char),
+        any::<String>().prop_map(Value::from),
+        any::<bool>().prop_map(Value::Boolean),
+        vec(any::<i64>(), 0..8).prop_map(Value::from),
+        vec(number(), 0..8).prop_map(Value::from),
+        vec(any::<String>(), 0..8).prop_map(Value::from),
+        vec(any::<bool>(), 0..8).prop_map(Value::from),
+    ]
+}
+
+// Two values of the same type
+fn value_pair() -> impl Strategy<Value = (Value, Value)> {
+    prop_oneof![
+        (any::<i64>(), any::<i64>()).prop_map(|(a, b)| (Value::Integer(a), Value::Integer(b))),
+        (number(), number()).prop_map(|(a, b)| (Value::Number(a), Value::Number(b))),
+        (any::<char>(), any::<char>()).prop_map(|(a, b)| (Value::Char(a), Value::Char(b))),
+        ("[a-c]{0,3}", "[a-c]{0,3}").prop_map(|(a, b)| (Value::from(a), Value::from(b))),
+        (any::<bool>(), any::<bool>()).prop_map(|(a, b)| (Value::Boolean(a), Value::Boolean(b))),
+        (vec(0..3i64, 0..3), vec(0..3i64, 0..3))
+            .prop_map(|(a, b)| (Value::from(a), Value::from(b))),
+    ]
+}
+
+// An array of at least `min_size` elements and a value which can be added to it
+fn array_and_element(min_size: usize) -> impl Strategy<Value = (Value, Value)> {
+    let size = min_size..min_size + 8;
+    prop_oneof![
+        (vec(any::<i64>(), size.clone()), any::<i64>())
+            .prop_map(|(array, element)| (Value::from(array), Value::Integer(element))),
+        (vec(number(), size.clone()), number())
+            .prop_map(|(array, element)| (Value::from(array), Value::Number(element))),
+        (vec(any::<String>(), size.clone()), any::<String>())
+            .prop_map(|(array, element)| (Value::from(array), Value::from(element))),
+        (vec(any::<bool>(), size), any::<bool>())
+            .prop_map(|(array, element)| (Value::from(array), Value::Boolean(element))),
+    ]
+}
+
+fn size_of(memory: &mut Memory, array: &str) -> i64 {
+    run(memory, &format!("SIZE {} size", array)).expect("An array");
+    match load(memory, "size") {
+        Value::Integer(size) => size,
+        other => panic!("SIZE gave {:?}", other),
+    }
+}
+
+fn compare(memory: &mut Memory, comparison: &str, a: &str, b: &str) -> bool {
+    run(
+        memory,
+        &format!("COMPARE_{} {} {} result", comparison, a, b),
+    )
+    .expect("Same types");
+    load(memory, "result") == Value::Boolean(true)
+}
+
+proptest! {
+    #[test]
+    fn add_then_substract_gives_the_value_back(a in -(1i64 << 40)..1 << 40, b in -(1i64 << 40)..1 << 40) {
+        let mut memory = Memory::default();
+        memory.store("a", Value::Integer(a));
+        memory.store("b", Value::Integer(b));
+        // Integers stored in a NUMBER are exact
+        memory.store("x", Value::Number(a as f64));
+        memory.store("y", Value::Number(b as f64));
+
+        run(&mut memory, "ADD a b sum").unwrap();
+        run(&mut memory, "SUBSTRACT sum b result").unwrap();
+        prop_assert_eq!(load(&memory, "result"), Value::Integer(a));
+        run(&mut memory, "ADD x y sum").unwrap();
+        run(&mut memory, "SUBSTRACT sum y result").unwrap();
+        prop_assert_eq!(load(&memory, "result"), Value::Number(a as f64));
+    }
+
+    #[test]
+    fn push_back_adds_one_element((array, element) in array_and_element(0)) {
+        let mut memory = Memory::default();
+        memory.store("array", array);
+        memory.store("element", element.clone());
+
+        let size = size_of(&mut memory, "array");
+        run(&mut memory, "PUSH_BACK array element").unwrap();
+        prop_assert_eq!(size_of(&mut memory, "array"), size + 1);
+        for last in [size, -1] {
+            memory.store("last", Value::Integer(last));
+            run(&mut memory, "GET_AT array last result").unwrap();
+            prop_assert_eq!(load(&memory, "result"), element.clone());
+        }
+    }
+
+    #[test]
+    fn erase_undoes_insert(
+        // Every index where an element can be inserted, from the end too
+        ((array, element), index) in array_and_element(0)
+            .prop_flat_map(|(array, element)| {
+                let size = match &array {
+                    Value::ArrayOfInteger(array) => array.len(),
+                    Value::ArrayOfNumber(array) => array.len(),
+                    Value::ArrayOfString(array) => array.len(),
+                    Value::ArrayOfBoolean(array) => array.len(),
+                    _ => unreachable!("Only arrays are generated"),
+                };
+                let size = size as i64;
+                (Just((array, element)), -size..=size)
+            })
+    ) {
+        let mut memory = Memory::default();
+        memory.store("array", array.clone());
+        memory.store("element", element.clone());
+        memory.store("index", Value::Integer(index));
+
+        run(&mut memory, "INSERT array index element").unwrap();
+        // A negative index now points to the element before the inserted one
+        let inserted = if index < 0 { index - 1 } else { index };
+        memory.store("inserted", Value::Integer(inserted));
+        run(&mut memory, "GET_AT array inserted result").unwrap();
+        prop_assert_eq!(load(&memory, "result"), element);
+        run(&mut memory, "ERASE array inserted").unwrap();
+        prop_assert_eq!(load(&memory, "array"), array);
+    }
+
+    #[test]
+    fn comparisons_agree((a, b) in value_pair()) {
+        let mut memory = Memory::default();
+        memory.store("a", a);
+        memory.store("b", b);
+
+        let less = compare(&mut memory, "LESS", "a", "b");
+        let equal = compare(&mut memory, "EQUAL", "a", "b");
+        let greater = compare(&mut memory, "GREATER", "a", "b");
+        prop_assert_eq!([less, equal, greater].iter().filter(|result| **result).count(), 1);
+        prop_assert_eq!(compare(&mut memory, "DIFFERENT", "a", "b"), !equal);
+        prop_assert_eq!(compare(&mut memory, "LESS_OR_EQUAL", "a", "b"), less || equal);
+        prop_assert_eq!(compare(&mut memory, "GREATER_OR_EQUAL", "a", "b"), greater || equal);
+        prop_assert_eq!(compare(&mut memory, "GREATER", "b", "a"), less);
+        prop_assert!(compare(&mut memory, "EQUAL", "a", "a"));
+    }
+
+    #[test]
+    fn values_of_different_types_are_not_compared(a in value(), b in value()) {
+        prop_assume!(a.type_name() != b.type_name());
+        let mut memory = Memory::default();
+        memory.store("a", a);
+        memory.store("b", b);
+        let result = run(&mut memory, "COMPARE_EQUAL a b result");
+        prop_assert!(matches!(result, Err(VMError::MismatchingTypes(_, _))));
+    }
+
+    #[test]
+    fn copy_is_equal_and_independent(original in value()) {
+        let mut memory = Memory::default();
+        memory.store("original", original.clone());
+        run(&mut memory, "COPY original copy").unwrap();
+        prop_assert_eq!(load(&memory, "copy"), original.clone());
+
+        // Only strings and arrays share their content, they are modified in place
+        let element = match &original {
+            Value::String(_) => Some(Value::from("suffix")),
+            Value::ArrayOfInteger(_) => Some(Value::Integer(1)),
+            Value::ArrayOfNumber(_) => Some(Value::Number(1.0)),
+            Value::ArrayOfString(_) => Some(Value::from("element")),
+            Value::ArrayOfBoolean(_) => Some(Value::Boolean(true)),
+            _ => None,
+        };
+        if let Some(element) = element {
+            memory.store("element", element);
+            let line = match original {
+                Value::String(_) => "CONCAT copy element copy",
+                _ => "PUSH_BACK copy element",
+            };
+            run(&mut memory, line).unwrap();
+            prop_assert_eq!(load(&memory, "original"), original.clone());
+            prop_assert_ne!(load(&memory, "copy"), original);
+        }
+    }
+}
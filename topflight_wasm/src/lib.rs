@@ -1,5 +1,5 @@
-use wasm_bindgen::prelude::*;
 use std::fmt;
+use wasm_bindgen::prelude::*;
 
 #[derive(Debug)]
 struct Error {
@@ -18,33 +18,75 @@ impl fmt::Display for Error {
     }
 }
 
-fn execute_code_impl(code: String) -> Result<String, Error> {
+// Files that can be imported by the code, there is no file system in the browser
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct FileMap {
+    files: topflight_core::VirtualFiles,
+}
+
+#[wasm_bindgen]
+impl FileMap {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> FileMap {
+        FileMap::default()
+    }
+
+    pub fn add(&mut self, path: &str, source: String) {
+        self.files.add(path, source);
+    }
+}
+
+fn execute_code_impl(
+    code: String,
+    files: topflight_core::VirtualFiles,
+    max_steps: Option<u32>,
+    max_memory: Option<u32>,
+) -> Result<String, Error> {
     let mut output = String::new();
-    let mut memory = topflight_core::Memory::default();
-    let mut routines = topflight_core::Routines::new();
-    let mut routine_in_construction: Option<topflight_core::Routine> = Option::None;
-    let lines = code.split('\n');
-    for (i, line) in lines.enumerate() {
-        if let Err(error) = topflight_core::handle_line(
-            line,
-            &mut memory,
-            &mut routines,
-            &mut routine_in_construction,
-            &mut output,
-        ) {
-            return Err(Error {
-                wrapped_error: error,
-                line_number: i + 1,
-                line: line.to_string(),
-            });
+    let mut interpreter = topflight_core::Interpreter::default();
+    interpreter.set_file_loader(files);
+    interpreter.limits.max_steps = max_steps.map(u64::from);
+    interpreter
+        .memory
+        .set_max_size(max_memory.map(|max_memory| max_memory as usize));
+    let lines: Vec<&str> = code.lines().collect();
+    let error_at = |wrapped_error, line_number: usize| Error {
+        wrapped_error,
+        line_number,
+        line: lines[line_number - 1].to_string(),
+    };
+
+    // Define every routine first so they can be called before their definition
+    let program = interpreter
+        .load_source(&code)
+        .map_err(|(error, line_number)| error_at(error, line_number))?;
+    for (line_number, instruction) in program {
+        if let Err(error) = interpreter.execute(&instruction, &mut output) {
+            return Err(error_at(error, line_number));
         }
     }
     Ok(output)
 }
 
+// Without a maximum number of steps an infinite loop freezes the page, the maximum memory is in bytes
+#[wasm_bindgen]
+pub fn execute_code(code: String, max_steps: Option<u32>, max_memory: Option<u32>) -> String {
+    let files = topflight_core::VirtualFiles::default();
+    match execute_code_impl(code, files, max_steps, max_memory) {
+        Ok(str) => str,
+        Err(error) => format!("{}", error),
+    }
+}
+
 #[wasm_bindgen]
-pub fn execute_code(code: String) -> String {
-    match execute_code_impl(code) {
+pub fn execute_code_with_files(
+    code: String,
+    files: &FileMap,
+    max_steps: Option<u32>,
+    max_memory: Option<u32>,
+) -> String {
+    match execute_code_impl(code, files.files.clone(), max_steps, max_memory) {
         Ok(str) => str,
         Err(error) => format!("{}", error),
     }